@@ -1,13 +1,11 @@
-drop table if exists tag cascade;
-drop table if exists question cascade;
-drop table if exists tag_question cascade;
+-- Tags, scraped questions and the relation between them, with a sample question.
 
-create table tag (
+create table if not exists tag (
   tag_id serial primary key,
   tag_title varchar(30) not null unique
 );
 
-create table question (
+create table if not exists question (
   question_id serial primary key,
   title varchar(200) not null,
   q_description varchar(1000) not null,
@@ -18,13 +16,13 @@ create table question (
   answer integer not null
 );
 
-create table tag_question (
+create table if not exists tag_question (
   tag_id integer references tag (tag_id) on update cascade on delete cascade,
   question_id integer references question (question_id) on update cascade,
   constraint tag_question_pkey primary key (tag_id,question_id)
 );
- 
-insert into tag (tag_title) values ('python'),('rust');
+
+insert into tag (tag_title) values ('python'),('rust') on conflict do nothing;
 
 insert into question (title,q_description,question_link,votes,stack_id,views,answer) values (
   'i dont know rust',
@@ -32,9 +30,10 @@ insert into question (title,q_description,question_link,votes,stack_id,views,ans
   'https://stackoverflow.com/questions/21716853/error-syntax-error-at-or-near-when-creating-a-new-table',
   900,
   898765,
-  '1 million',
+  '1000000',
   67
-);
-
+) on conflict do nothing;
 
-insert into tag_question (tag_id,question_id) values (1,1);
+insert into tag_question (tag_id,question_id)
+select t.tag_id, q.question_id from tag t, question q where t.tag_title = 'python' and q.stack_id = 898765
+on conflict do nothing;
@@ -0,0 +1,13 @@
+-- Tags the scheduler scrapes, picked randomly by weight.
+
+create table if not exists scrape_target (
+  target_id serial primary key,
+  tag_title varchar(30) not null unique,
+  tab varchar(10) not null default 'Newest',
+  weight integer not null default 1 check (weight > 0),
+  enabled boolean not null default true
+);
+
+insert into scrape_target (tag_title)
+select tag_title from (values ('python'),('rust'),('c#'),('android'),('html'),('javascript')) as seed (tag_title)
+where not exists (select 1 from scrape_target);
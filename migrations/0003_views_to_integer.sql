@@ -0,0 +1,24 @@
+-- Stores the scraped view strings like '1,234', '12k' or '1.2m' as integers.
+-- Running it again on an already converted table leaves the values untouched.
+
+create or replace function pg_temp.parse_views(raw text) returns integer as $$
+declare
+  cleaned text := lower(regexp_replace(raw, '[\s,]', '', 'g'));
+begin
+  if cleaned ~ '^[0-9]+(\.[0-9]+)?k$' then
+    return round(left(cleaned, -1)::numeric * 1000);
+  elsif cleaned ~ '^[0-9]+(\.[0-9]+)?m$' then
+    return round(left(cleaned, -1)::numeric * 1000000);
+  elsif cleaned ~ '^[0-9]+(\.[0-9]+)?million$' then
+    return round(left(cleaned, -7)::numeric * 1000000);
+  elsif cleaned ~ '^[0-9]+(\.[0-9]+)?$' then
+    return round(cleaned::numeric);
+  end if;
+  raise notice 'views % could not be parsed, storing 0', raw;
+  return 0;
+end;
+$$ language plpgsql;
+
+alter table question alter column views type integer using pg_temp.parse_views(views::text);
+
+create index if not exists question_views_idx on question (views);
@@ -0,0 +1,12 @@
+-- Adds the full question body and the answer table used by SCRAPER.FETCH_DETAILS.
+
+alter table question add column if not exists body_html text;
+
+create table if not exists answer (
+  answer_id serial primary key,
+  question_id integer not null references question (question_id) on update cascade on delete cascade,
+  stack_answer_id integer not null unique,
+  body_html text not null,
+  score integer not null,
+  is_accepted boolean not null default false
+);
@@ -0,0 +1,9 @@
+-- Lets every scrape target pick its question source and Stack Exchange site.
+
+alter table scrape_target add column if not exists source varchar(10) not null default 'html';
+alter table scrape_target add column if not exists site varchar(100) not null default 'stackoverflow';
+alter table scrape_target add column if not exists dump_path varchar(255);
+
+alter table scrape_target drop constraint if exists scrape_target_tag_title_key;
+alter table scrape_target drop constraint if exists scrape_target_unique;
+alter table scrape_target add constraint scrape_target_unique unique (tag_title,site,source);
@@ -0,0 +1,17 @@
+-- Adds the question_snapshot table recording the statistics of every scrape.
+-- The current statistics of the stored questions become their first snapshot.
+
+create table if not exists question_snapshot (
+  snapshot_id serial primary key,
+  question_id integer not null references question (question_id) on update cascade on delete cascade,
+  votes integer not null,
+  answer integer not null,
+  views integer not null,
+  observed_at timestamptz not null default now()
+);
+
+create index if not exists question_snapshot_observed_idx on question_snapshot (question_id, observed_at);
+
+insert into question_snapshot (question_id,votes,answer,views)
+select question_id, votes, answer, views from question q
+where not exists (select 1 from question_snapshot s where s.question_id = q.question_id);
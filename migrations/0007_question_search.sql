@@ -0,0 +1,9 @@
+-- Full-text search over the question titles and descriptions, titles weigh more.
+
+alter table question add column if not exists search_vector tsvector
+  generated always as (
+    setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+    setweight(to_tsvector('english', coalesce(q_description, '')), 'B')
+  ) stored;
+
+create index if not exists question_search_idx on question using gin (search_vector);
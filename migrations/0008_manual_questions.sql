@@ -0,0 +1,8 @@
+-- Hand-written questions have no stack id and no link, deleting a question removes its tag relations.
+
+alter table question alter column stack_id drop not null;
+alter table question alter column question_link drop not null;
+
+alter table tag_question drop constraint if exists tag_question_question_id_fkey;
+alter table tag_question add constraint tag_question_question_id_fkey
+  foreign key (question_id) references question (question_id) on update cascade on delete cascade;
@@ -0,0 +1,27 @@
+-- Tag titles are unique regardless of case, and synonyms point to the tag scraped questions get instead.
+-- Tags differing only in case are collapsed into the oldest one first.
+
+insert into tag_question (tag_id,question_id)
+select keep.tag_id, tq.question_id
+from tag_question tq
+join tag duplicate on duplicate.tag_id = tq.tag_id
+join lateral (
+  select min(t.tag_id) as tag_id from tag t where lower(t.tag_title) = lower(duplicate.tag_title)
+) keep on keep.tag_id <> duplicate.tag_id
+on conflict do nothing;
+
+delete from tag duplicate
+where exists (
+  select 1 from tag t where lower(t.tag_title) = lower(duplicate.tag_title) and t.tag_id < duplicate.tag_id
+);
+
+alter table tag drop constraint if exists tag_tag_title_key;
+create unique index if not exists tag_title_lower_idx on tag (lower(tag_title));
+
+create table if not exists tag_synonym (
+  synonym_id serial primary key,
+  synonym varchar(30) not null,
+  tag_id integer not null references tag (tag_id) on update cascade on delete cascade
+);
+
+create unique index if not exists tag_synonym_lower_idx on tag_synonym (lower(synonym));
@@ -0,0 +1,21 @@
+-- Adds the scrape_run table recording the outcome of every scrape run.
+-- A run stays 'running' until it finished, one left running was interrupted.
+
+create table if not exists scrape_run (
+  run_id serial primary key,
+  target_id integer references scrape_target (target_id) on delete set null,
+  url varchar(500) not null,
+  status varchar(10) not null default 'running',
+  started_at timestamptz not null default now(),
+  finished_at timestamptz,
+  questions_seen integer not null default 0,
+  questions_new integer not null default 0,
+  questions_updated integer not null default 0,
+  tags_created integer not null default 0,
+  details_stored integer not null default 0,
+  skipped integer not null default 0,
+  rolled_back integer not null default 0,
+  errors text[] not null default '{}'
+);
+
+create index if not exists scrape_run_started_idx on scrape_run (started_at desc);
@@ -0,0 +1,21 @@
+-- Adds the scrape_job table holding the on-demand scrape jobs.
+-- A job is queued, then running, and ends as succeeded or failed. Its scrape run holds the counts.
+
+create table if not exists scrape_job (
+  job_id serial primary key,
+  tag_title varchar(30) not null,
+  tab varchar(10) not null,
+  site varchar(100) not null,
+  source varchar(10) not null,
+  dump_path varchar(255),
+  pages integer not null,
+  status varchar(10) not null default 'queued',
+  pages_done integer not null default 0,
+  run_id integer references scrape_run (run_id) on delete set null,
+  error text,
+  created_at timestamptz not null default now(),
+  started_at timestamptz,
+  finished_at timestamptz
+);
+
+create index if not exists scrape_job_queued_idx on scrape_job (job_id) where status = 'queued';
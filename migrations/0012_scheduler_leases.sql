@@ -0,0 +1,9 @@
+-- Adds the scheduler_lease table. Replicas sharing the database claim every tick of a scheduled
+-- job here, so each tick runs on the one instance which claimed it.
+
+create table if not exists scheduler_lease (
+  job_name varchar(100) primary key,
+  last_tick timestamptz not null,
+  holder varchar(100) not null,
+  acquired_at timestamptz not null default now()
+);
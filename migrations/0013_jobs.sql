@@ -0,0 +1,20 @@
+-- Adds the jobs table of the background job queue.
+-- A job is queued until its run_at, running while a worker holds it until locked_until, and ends
+-- as succeeded or, once all of its attempts failed, as dead.
+
+create table if not exists jobs (
+  job_id bigserial primary key,
+  kind varchar(50) not null,
+  payload jsonb not null default '{}',
+  status varchar(10) not null default 'queued',
+  attempts integer not null default 0,
+  max_attempts integer not null,
+  run_at timestamptz not null default now(),
+  locked_until timestamptz,
+  locked_by varchar(100),
+  last_error text,
+  created_at timestamptz not null default now(),
+  finished_at timestamptz
+);
+
+create index if not exists jobs_pending_idx on jobs (run_at) where status in ('queued', 'running');
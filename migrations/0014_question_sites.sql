@@ -0,0 +1,18 @@
+-- Stack ids are only unique within a Stack Exchange site, so questions and answers remember their site
+-- and are keyed by it. Scraped rows get the site of their Stack Exchange link, every other one stackoverflow.
+
+alter table question add column if not exists site varchar(100) not null default 'stackoverflow';
+update question set site = regexp_replace(substring(question_link from '^https?://([^/]+)'), '(\.stackexchange)?\.(com|net)$', '')
+  where stack_id is not null
+  and question_link ~ '^https?://((stackoverflow|serverfault|superuser|askubuntu|stackapps)\.com|mathoverflow\.net|[a-z0-9-]+\.stackexchange\.com)/';
+
+alter table question drop constraint if exists question_stack_id_key;
+alter table question drop constraint if exists question_site_stack_id_key;
+alter table question add constraint question_site_stack_id_key unique (site, stack_id);
+
+alter table answer add column if not exists site varchar(100) not null default 'stackoverflow';
+update answer a set site = q.site from question q where q.question_id = a.question_id;
+
+alter table answer drop constraint if exists answer_stack_answer_id_key;
+alter table answer drop constraint if exists answer_site_stack_answer_id_key;
+alter table answer add constraint answer_site_stack_answer_id_key unique (site, stack_answer_id);
@@ -0,0 +1,11 @@
+-- A running scrape job is leased to its worker until locked_until and renewed while it runs.
+-- Jobs whose lease ran out, because their instance died, are taken over by the next free worker.
+
+alter table scrape_job add column if not exists locked_by varchar(100);
+alter table scrape_job add column if not exists locked_until timestamptz;
+
+-- jobs left running before leases existed are taken over right away
+update scrape_job set locked_until = now() where status = 'running' and locked_until is null;
+
+drop index if exists scrape_job_queued_idx;
+create index if not exists scrape_job_pending_idx on scrape_job (job_id) where status in ('queued', 'running');
@@ -1,7 +1,14 @@
 use crate::db;
+use crate::job_queue::QUESTION_DETAIL_JOB;
 use crate::error::AppError;
-use crate::models::{AppState, CreateTag, ResultResponse, Tag};
-use actix_web::{web, HttpResponse, Responder};
+use crate::handlers::question_detail_url;
+use crate::models::{
+    AppState, CreateQuestion, JobQuery, QuestionDetailJob, CreateScrapeJob, CreateScrapeTarget, CreateTag, CreateTagSynonym, QuestionPage, QuestionQuery, ResultResponse,
+    RisingQuery, ScrapeRunQuery, ScrapeTarget, SearchPage, SearchQuery, SynonymCreated, TagMerge,
+    UpdateQuestion, UpdateTag,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
 use deadpool_postgres::{Client, Pool};
 use slog::{crit, info, o, Logger};
 use validator::Validate;
@@ -39,13 +46,73 @@ pub async fn get_tags(state: web::Data<AppState>) -> Result<impl Responder, AppE
     result.map(|tags| HttpResponse::Ok().json(tags))
 }
 
-pub async fn get_questions(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+#[derive(Serialize)]
+struct PageParams<'a, T> {
+    page: i64,
+    #[serde(flatten)]
+    query: &'a T,
+}
+
+// Link to another page of the same query
+fn page_link<T: Serialize>(req: &HttpRequest, query: &T, page: i64) -> String {
+    let params = serde_urlencoded::to_string(PageParams { page, query }).unwrap();
+    format!("{}?{}", req.path(), params)
+}
+
+pub async fn get_questions(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<QuestionQuery>,
+) -> Result<impl Responder, AppError> {
     let sublog = state.log.new(o!("handler" => "get_questions"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
     let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::get_questions_page(&client, &query).await;
 
-    let result = db::get_questions(&client).await;
+    result.map(|(questions, total)| {
+        let next = (query.page * query.limit < total).then(|| page_link(&req, &*query, query.page + 1));
+        let previous = (query.page > 1).then(|| page_link(&req, &*query, query.page - 1));
+        HttpResponse::Ok().json(QuestionPage {
+            questions,
+            total,
+            page: query.page,
+            limit: query.limit,
+            next,
+            previous,
+        })
+    })
+}
 
-    result.map(|questions| HttpResponse::Ok().json(questions))
+pub async fn search_questions(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "search_questions"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::search_questions(&client, &query).await;
+
+    result.map(|(results, total)| {
+        let next = (query.page * query.limit < total).then(|| page_link(&req, &*query, query.page + 1));
+        let previous = (query.page > 1).then(|| page_link(&req, &*query, query.page - 1));
+        HttpResponse::Ok().json(SearchPage {
+            results,
+            total,
+            page: query.page,
+            limit: query.limit,
+            next,
+            previous,
+        })
+    })
 }
 
 // we can use the actix web extracter to get the param
@@ -61,6 +128,135 @@ pub async fn get_questions_by_tag(
     result.map(|questions| HttpResponse::Ok().json(questions))
 }
 
+pub async fn get_question(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_question"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_question(&**client, path.0).await;
+
+    result.map(|question| HttpResponse::Ok().json(question))
+}
+
+// The question and its tags are written in one transaction
+pub async fn create_question(
+    state: web::Data<AppState>,
+    json: web::Json<CreateQuestion>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "create_question"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let transaction = client.transaction().await?;
+    let question_id = db::create_question(&*transaction, &json).await?;
+    let tag_ids = db::resolve_tags(&*transaction, &json.tags).await?;
+    db::reconcile_question_tags(&*transaction, question_id, &tag_ids).await?;
+    let question = db::get_question(&*transaction, question_id).await?;
+    transaction.commit().await?;
+    info!(sublog, "Created question {}", question_id);
+
+    Ok(HttpResponse::Ok().json(question))
+}
+
+pub async fn replace_question(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    json: web::Json<CreateQuestion>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "replace_question"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let question_id = path.0;
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let transaction = client.transaction().await?;
+    db::replace_question(&*transaction, question_id, &json).await?;
+    let tag_ids = db::resolve_tags(&*transaction, &json.tags).await?;
+    db::reconcile_question_tags(&*transaction, question_id, &tag_ids).await?;
+    let question = db::get_question(&*transaction, question_id).await?;
+    transaction.commit().await?;
+
+    Ok(HttpResponse::Ok().json(question))
+}
+
+pub async fn patch_question(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    json: web::Json<UpdateQuestion>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "patch_question"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let question_id = path.0;
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let transaction = client.transaction().await?;
+    db::patch_question(&*transaction, question_id, &json).await?;
+    if let Some(tags) = &json.tags {
+        let tag_ids = db::resolve_tags(&*transaction, tags).await?;
+        db::reconcile_question_tags(&*transaction, question_id, &tag_ids).await?;
+    }
+    let question = db::get_question(&*transaction, question_id).await?;
+    transaction.commit().await?;
+
+    Ok(HttpResponse::Ok().json(question))
+}
+
+pub async fn delete_question(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "delete_question"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::delete_question(&client, path.0).await;
+
+    result.map(|_| {
+        HttpResponse::Ok().json(ResultResponse {
+            message: "question deleted".to_string(),
+            success: true,
+        })
+    })
+}
+
+pub async fn get_question_history(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_question_history"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_question_history(&client, path.0).await;
+
+    result.map(|snapshots| HttpResponse::Ok().json(snapshots))
+}
+
+// query parameters are extracted just like the json body
+pub async fn get_rising_questions(
+    state: web::Data<AppState>,
+    query: web::Query<RisingQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_rising_questions"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::get_rising_questions(&client, query.hours, query.limit).await;
+
+    result.map(|questions| HttpResponse::Ok().json(questions))
+}
+
 // we use json extractor to extract data from body
 // in the generics it contains the DTO(data transfer object) to exttract the values
 pub async fn create_tag(
@@ -70,7 +266,7 @@ pub async fn create_tag(
     let sublog = state.log.new(o!("handler" => "create_tag"));
 
     let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
-    let is_valid = json.validate().map_err(|err| AppError::from(err));
+    let is_valid = json.validate().map_err(AppError::from);
     match is_valid {
         Ok(_) => {
             let result = db::create_tag(&client, json.tag_title.clone()).await;
@@ -86,17 +282,286 @@ pub async fn create_tag(
 
 pub async fn update_tag(
     state: web::Data<AppState>,
-    json: web::Json<Tag>,
+    json: web::Json<UpdateTag>,
 ) -> Result<impl Responder, AppError> {
     let sublog = state.log.new(o!("handler" => "update_tag"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
     let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
 
-    let result = db::update_tag(&client, json.tag_id.clone(), json.tag_title.clone()).await;
+    let result = db::update_tag(&client, json.tag_id, json.tag_title.clone()).await;
 
-    result.map(|updated| {
+    result.map(|_| {
         HttpResponse::Ok().json(ResultResponse {
             message: "operation completed".to_string(),
-            success: updated,
+            success: true,
         })
     })
 }
+
+pub async fn delete_tag(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "delete_tag"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::delete_tag(&client, path.0).await;
+
+    result.map(|_| {
+        HttpResponse::Ok().json(ResultResponse {
+            message: "tag deleted".to_string(),
+            success: true,
+        })
+    })
+}
+
+pub async fn merge_tag(
+    state: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "merge_tag"));
+    let (tag_id, into_tag_id) = path.into_inner();
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let transaction = client.transaction().await?;
+    let merged = db::get_tag(&*transaction, tag_id).await?;
+    let tag = db::get_tag(&*transaction, into_tag_id).await?;
+    let questions_moved = db::merge_tag(&*transaction, tag_id, into_tag_id).await?;
+    transaction.commit().await?;
+    info!(sublog, "Merged tag {} into {}", merged.tag_title, tag.tag_title);
+
+    Ok(HttpResponse::Ok().json(TagMerge {
+        tag,
+        questions_moved,
+    }))
+}
+
+pub async fn get_tag_synonyms(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_tag_synonyms"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_tag_synonyms(&client).await;
+
+    result.map(|synonyms| HttpResponse::Ok().json(synonyms))
+}
+
+// Tags already named like the new synonym are merged into its tag right away
+pub async fn create_tag_synonym(
+    state: web::Data<AppState>,
+    json: web::Json<CreateTagSynonym>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "create_tag_synonym"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let transaction = client.transaction().await?;
+    let synonym = db::create_tag_synonym(&*transaction, &json).await?;
+    let collapsed = db::collapse_synonym_tags(&*transaction).await?;
+    transaction.commit().await?;
+    for tag in &collapsed {
+        info!(sublog, "Collapsed tag {} into {}", tag.tag_title, tag.into_tag_id);
+    }
+
+    Ok(HttpResponse::Ok().json(SynonymCreated { synonym, collapsed }))
+}
+
+pub async fn delete_tag_synonym(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "delete_tag_synonym"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::delete_tag_synonym(&client, path.0).await;
+
+    result.map(|_| {
+        HttpResponse::Ok().json(ResultResponse {
+            message: "tag synonym deleted".to_string(),
+            success: true,
+        })
+    })
+}
+
+// Merges every tag named like a synonym into the tag of the synonym
+pub async fn collapse_tags(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "collapse_tags"));
+    let mut client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let transaction = client.transaction().await?;
+    let collapsed = db::collapse_synonym_tags(&*transaction).await?;
+    transaction.commit().await?;
+
+    Ok(HttpResponse::Ok().json(collapsed))
+}
+
+pub async fn get_scrape_targets(state: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_scrape_targets"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_scrape_targets(&client).await;
+
+    result.map(|targets| HttpResponse::Ok().json(targets))
+}
+
+pub async fn create_scrape_target(
+    state: web::Data<AppState>,
+    json: web::Json<CreateScrapeTarget>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "create_scrape_target"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::create_scrape_target(&client, &json).await;
+
+    result.map(|target| HttpResponse::Ok().json(target))
+}
+
+pub async fn update_scrape_target(
+    state: web::Data<AppState>,
+    json: web::Json<ScrapeTarget>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "update_scrape_target"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::update_scrape_target(&client, &json).await;
+
+    result.map(|_| {
+        HttpResponse::Ok().json(ResultResponse {
+            message: "operation completed".to_string(),
+            success: true,
+        })
+    })
+}
+
+pub async fn delete_scrape_target(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "delete_scrape_target"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::delete_scrape_target(&client, path.0).await;
+
+    result.map(|_| {
+        HttpResponse::Ok().json(ResultResponse {
+            message: "scrape target deleted".to_string(),
+            success: true,
+        })
+    })
+}
+
+// Latest scrape runs, to tell whether the scheduled ingestion is healthy
+pub async fn get_scrape_runs(
+    state: web::Data<AppState>,
+    query: web::Query<ScrapeRunQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_scrape_runs"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::get_scrape_runs(&client, query.limit).await;
+
+    result.map(|runs| HttpResponse::Ok().json(runs))
+}
+
+// The job runs in the background, its id is polled for the progress. Progress is also pushed to
+// the SSE clients as `scrape_job` events
+pub async fn enqueue_scrape(
+    state: web::Data<AppState>,
+    json: web::Json<CreateScrapeJob>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "enqueue_scrape"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = state.scrape_queue.enqueue(&client, &json).await;
+
+    result.map(|job| HttpResponse::Accepted().json(job))
+}
+
+pub async fn get_scrape_job(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_scrape_job"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_scrape_job(&client, path.0).await;
+
+    result.map(|job| HttpResponse::Ok().json(job))
+}
+
+// Refreshes the body and the answers of a question in the background, the job is returned
+pub async fn refresh_question_detail(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "refresh_question_detail"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let question = db::get_question(&**client, path.0).await?;
+    question_detail_url(&question)?;
+    let job = QuestionDetailJob { question_id: path.0 };
+    let result = state.jobs.enqueue(&client, QUESTION_DETAIL_JOB, &job).await;
+
+    result.map(|job| HttpResponse::Accepted().json(job))
+}
+
+pub async fn get_jobs(
+    state: web::Data<AppState>,
+    query: web::Query<JobQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_jobs"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+    let result = db::get_jobs(&client, &query).await;
+
+    result.map(|jobs| HttpResponse::Ok().json(jobs))
+}
+
+pub async fn get_job(
+    state: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_job"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_job(&client, path.0).await;
+
+    result.map(|job| HttpResponse::Ok().json(job))
+}
+
+// Dead jobs are queued again with all of their attempts, other jobs answer 409
+pub async fn retry_job(
+    state: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "retry_job"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::retry_job(&client, path.0).await;
+
+    result.map(|job| HttpResponse::Ok().json(job))
+}
@@ -83,4 +83,15 @@ impl Broadcaster {
         // disconnected clients will get swept up by `remove_stale_clients`
         let _ = future::join_all(send_futures).await;
     }
+
+    /// Broadcasts `msg` as an event named `event`, clients listen for it by that name.
+    pub async fn broadcast_event(&self, event: &str, msg: &str) {
+        let clients = self.inner.lock().clients.clone();
+
+        let send_futures = clients
+            .iter()
+            .map(|client| client.send(sse::Data::new(msg).event(event)));
+
+        let _ = future::join_all(send_futures).await;
+    }
 }
\ No newline at end of file
@@ -7,10 +7,146 @@ pub struct ServerConfig {
   pub port: i32,
 }
 
+// Controls how much a single scrape run collects, every field falls back to its default
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ScraperConfig {
+  // questions collected per run across all listing pages
+  pub total: usize,
+  // upper bound on the listing pages walked per run
+  pub max_pages: usize,
+  // a walk of the Newest tab ends at the first question which is stored already
+  pub stop_at_known: bool,
+  // fetches the question page of every new question for its full body and answers
+  pub fetch_details: bool,
+  // Stack Exchange API used by the api scrape targets
+  pub api_base: String,
+  // optional app key, raises the daily quota of the API
+  pub api_key: Option<String>,
+  // API filter deciding which question fields are returned
+  pub api_filter: String,
+  // sent with every request so site owners know who is scraping
+  pub user_agent: String,
+  // a request taking longer than this fails
+  pub timeout_secs: u64,
+  // sustained request rate across all scrape requests
+  pub requests_per_minute: u32,
+  // requests which may be sent at once before the rate applies
+  pub burst: u32,
+  // retries of a request answered with 429 or 5xx
+  pub max_retries: u32,
+  // first retry delay when there is no Retry-After, doubled on every retry
+  pub retry_base_ms: u64,
+  // skips the pages disallowed by the robots.txt of the site
+  pub respect_robots: bool,
+  // directory the dump_path of the dump scrape targets is relative to, no other file is read
+  pub dump_dir: String,
+}
+
+impl Default for ScraperConfig {
+  fn default() -> Self {
+    ScraperConfig {
+      total: 10,
+      max_pages: 10,
+      stop_at_known: true,
+      fetch_details: false,
+      api_base: "https://api.stackexchange.com/2.3".to_string(),
+      api_key: None,
+      api_filter: "withbody".to_string(),
+      user_agent: format!(
+        "actix-question-bank/{} (+https://github.com/chaudharypraveen98/actix-question-bank-stackoverflow)",
+        env!("CARGO_PKG_VERSION")
+      ),
+      timeout_secs: 30,
+      requests_per_minute: 20,
+      burst: 3,
+      max_retries: 3,
+      retry_base_ms: 1000,
+      respect_robots: true,
+      dump_dir: "./dumps".to_string(),
+    }
+  }
+}
+
+// A scheduled job, the cron expression starts with a seconds field
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct JobConfig {
+  pub cron: String,
+  // random delay of up to this many seconds added to every tick
+  pub jitter_secs: u64,
+  pub enabled: bool,
+}
+
+impl Default for JobConfig {
+  fn default() -> Self {
+    JobConfig {
+      cron: "1/50 * * * * * *".to_string(),
+      jitter_secs: 0,
+      enabled: true,
+    }
+  }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SchedulerConfig {
+  // scrapes a random scrape target
+  pub scrape: JobConfig,
+  // names this replica in the scheduler leases, every replica needs its own
+  pub instance: String,
+}
+
+impl Default for SchedulerConfig {
+  fn default() -> Self {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "scheduler".to_string());
+    SchedulerConfig {
+      scrape: JobConfig::default(),
+      instance: format!("{}-{:08x}", host, rand::random::<u32>()),
+    }
+  }
+}
+
+// Background job queue, the jobs are stored in the jobs table
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct JobQueueConfig {
+  // worker tasks taking jobs from the queue
+  pub workers: usize,
+  // how often idle workers look for jobs enqueued by other instances or due for a retry
+  pub poll_secs: u64,
+  // a job is given up by its worker after this long and taken by another one
+  pub visibility_timeout_secs: u64,
+  // attempts before a job is dead
+  pub max_attempts: i32,
+  // delay before the first retry, doubled on every further retry
+  pub backoff_base_secs: u64,
+  pub backoff_max_secs: u64,
+}
+
+impl Default for JobQueueConfig {
+  fn default() -> Self {
+    JobQueueConfig {
+      workers: 2,
+      poll_secs: 5,
+      visibility_timeout_secs: 300,
+      max_attempts: 5,
+      backoff_base_secs: 10,
+      backoff_max_secs: 3600,
+    }
+  }
+}
+
 #[derive(Deserialize)]
 pub struct Config {
   pub server: ServerConfig,
   pub pg: deadpool_postgres::Config,
+  #[serde(default)]
+  pub scraper: ScraperConfig,
+  #[serde(default)]
+  pub scheduler: SchedulerConfig,
+  #[serde(default)]
+  pub jobs: JobQueueConfig,
 }
 
 impl Config {
@@ -20,3 +156,15 @@ impl Config {
     cfg.try_into()
   }
 }
+
+// Pool for the tests that need the database from .env. They are ignored by default,
+// run them with cargo test <name> -- --ignored
+#[cfg(test)]
+pub fn test_pool() -> deadpool_postgres::Pool {
+  dotenv::dotenv().ok();
+  let config = Config::from_env().unwrap();
+  config
+    .pg
+    .create_pool(Some(deadpool_postgres::Runtime::Tokio1), tokio_postgres::NoTls)
+    .unwrap()
+}
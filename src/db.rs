@@ -1,9 +1,18 @@
 use crate::{
     error::{AppError, AppErrorType},
-    models::{QuestionId, Questions, ScrapedQuestion, Tag, TagQuestion, TagQuestionRelation, TagId},
+    models::{
+        split_tags,
+        BulkStored, CollapsedTag, CreateQuestion, CreateScrapeJob, CreateScrapeTarget, CreateTagSynonym, Job, JobQuery, TagRef,
+        TagSynonym, UpdateQuestion, QuestionDetail, QuestionQuery, QuestionSnapshot, Questions,
+        QuestionWithTags, RisingQuestion, SearchQuery, SearchResult,
+        ScrapeJob, ScrapeRun, ScrapeSummary, ScrapeTarget, ScrapedAnswer, ScrapedQuestion, Tag, TagId, TagQuestionRelation, UpsertedQuestion,
+    },
 };
+use std::collections::{HashMap, HashSet};
+
 use deadpool_postgres::Client;
 use tokio_pg_mapper::FromTokioPostgresRow;
+use tokio_postgres::GenericClient;
 
 pub async fn get_tags(client: &Client) -> Result<Vec<Tag>, AppError> {
     let statement = client.prepare("select * from tag limit 10;").await?;
@@ -39,6 +48,297 @@ pub async fn get_questions(client: &Client) -> Result<Vec<Questions>, AppError>
     Ok(questions)
 }
 
+pub async fn get_question(
+    client: &impl GenericClient,
+    question_id: i32,
+) -> Result<QuestionWithTags, AppError> {
+    let statement = client
+        .prepare("select * from question where question_id = $1;")
+        .await?;
+    let row = client
+        .query_opt(&statement, &[&question_id])
+        .await?
+        .ok_or_else(|| question_not_found(question_id))?;
+    let question = Questions::from_row_ref(&row).unwrap();
+    let site = row.get("site");
+    let body_html = row.get("body_html");
+
+    let statement = client
+        .prepare("select t.tag_id, t.tag_title from tag t, tag_question tq where tq.tag_id = t.tag_id and tq.question_id = $1 order by t.tag_title;")
+        .await?;
+    let tags = client
+        .query(&statement, &[&question_id])
+        .await?
+        .iter()
+        .map(|row| Tag::from_row_ref(row).unwrap())
+        .collect::<Vec<Tag>>();
+
+    let statement = client
+        .prepare("select stack_answer_id, body_html, score, is_accepted from answer where question_id = $1 order by is_accepted desc, score desc;")
+        .await?;
+    let answers = client
+        .query(&statement, &[&question_id])
+        .await?
+        .iter()
+        .map(|row| ScrapedAnswer {
+            stack_answer_id: row.get("stack_answer_id"),
+            body_html: row.get("body_html"),
+            score: row.get("score"),
+            is_accepted: row.get("is_accepted"),
+        })
+        .collect::<Vec<ScrapedAnswer>>();
+
+    Ok(QuestionWithTags {
+        question,
+        site,
+        body_html,
+        tags,
+        answers,
+    })
+}
+
+fn question_not_found(question_id: i32) -> AppError {
+    AppError {
+        cause: None,
+        message: Some(format!("Question {} was not found", question_id)),
+        error_type: AppErrorType::NotFoundError,
+    }
+}
+
+// Tag ids of the given tags, tags given by title are created when missing
+pub async fn resolve_tags(
+    client: &impl GenericClient,
+    tags: &[TagRef],
+) -> Result<Vec<i32>, AppError> {
+    let mut tag_ids = Vec::new();
+    for tag in tags {
+        let tag_id = match tag {
+            TagRef::Id(tag_id) => {
+                let statement = client.prepare("select tag_id from tag where tag_id = $1;").await?;
+                client
+                    .query_opt(&statement, &[tag_id])
+                    .await?
+                    .ok_or_else(|| tag_not_found(*tag_id))?
+                    .get("tag_id")
+            }
+            TagRef::Title(title) => get_tag_id(client, title.trim().to_string()).await?.tag_id,
+        };
+        tag_ids.push(tag_id);
+    }
+    Ok(tag_ids)
+}
+
+pub async fn create_question(
+    client: &impl GenericClient,
+    question: &CreateQuestion,
+) -> Result<i32, AppError> {
+    let statement = client
+        .prepare("insert into question (title,q_description,question_link,votes,stack_id,views,answer) values ($1,$2,$3,$4,$5,$6,$7) returning question_id;")
+        .await?;
+    let row = client
+        .query_one(
+            &statement,
+            &[
+                &question.title,
+                &question.q_description,
+                &question.question_link,
+                &question.votes,
+                &question.stack_id,
+                &question.views,
+                &question.answer,
+            ],
+        )
+        .await?;
+    Ok(row.get("question_id"))
+}
+
+// Replaces every field of a question, the stack id included
+pub async fn replace_question(
+    client: &impl GenericClient,
+    question_id: i32,
+    question: &CreateQuestion,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update question set title = $2, q_description = $3, question_link = $4, votes = $5, stack_id = $6, views = $7, answer = $8 where question_id = $1;")
+        .await?;
+    let updated = client
+        .execute(
+            &statement,
+            &[
+                &question_id,
+                &question.title,
+                &question.q_description,
+                &question.question_link,
+                &question.votes,
+                &question.stack_id,
+                &question.views,
+                &question.answer,
+            ],
+        )
+        .await?;
+    match updated {
+        0 => Err(question_not_found(question_id)),
+        _ => Ok(()),
+    }
+}
+
+// Changes only the given fields of a question
+pub async fn patch_question(
+    client: &impl GenericClient,
+    question_id: i32,
+    question: &UpdateQuestion,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update question set title = coalesce($2, title), q_description = coalesce($3, q_description), question_link = coalesce($4, question_link),
+            votes = coalesce($5, votes), views = coalesce($6, views), answer = coalesce($7, answer) where question_id = $1;")
+        .await?;
+    let updated = client
+        .execute(
+            &statement,
+            &[
+                &question_id,
+                &question.title,
+                &question.q_description,
+                &question.question_link,
+                &question.votes,
+                &question.views,
+                &question.answer,
+            ],
+        )
+        .await?;
+    match updated {
+        0 => Err(question_not_found(question_id)),
+        _ => Ok(()),
+    }
+}
+
+// Its tag relations, snapshots and answers are deleted along
+pub async fn delete_question(client: &Client, question_id: i32) -> Result<(), AppError> {
+    let statement = client
+        .prepare("delete from question where question_id = $1;")
+        .await?;
+    match client.execute(&statement, &[&question_id]).await? {
+        0 => Err(question_not_found(question_id)),
+        _ => Ok(()),
+    }
+}
+
+// Filters shared by a page of questions and their total count.
+// $1 min votes, $2 unanswered only, $3 tag titles, $4 whether every tag must match.
+// A title is looked up like get_tag_id does, a synonym wins over a tag of that name and the case is ignored
+const QUESTION_FILTERS: &str = "where ($1::integer is null or q.votes >= $1)
+    and (not $2 or q.answer = 0)
+    and (cardinality($3::varchar[]) = 0 or (
+        select count(*) from unnest($3::varchar[]) as wanted (title)
+        where exists (
+            select 1 from tag_question tq where tq.question_id = q.question_id and tq.tag_id = coalesce(
+                (select s.tag_id from tag_synonym s where lower(s.synonym) = lower(wanted.title)),
+                (select t.tag_id from tag t where lower(t.tag_title) = lower(wanted.title))
+            )
+        )
+    ) >= (case when $4 then cardinality($3::varchar[]) else 1 end))";
+
+// One page of the questions matching the query with the number of matches across all pages
+pub async fn get_questions_page(
+    client: &Client,
+    query: &QuestionQuery,
+) -> Result<(Vec<Questions>, i64), AppError> {
+    // the sort is validated against QUESTION_SORTS, ties are broken by the question id
+    let order = match query.sort.as_str() {
+        "votes" => "q.votes desc, q.question_id desc",
+        "answers" => "q.answer desc, q.question_id desc",
+        "views" => "q.views desc, q.question_id desc",
+        // stack ids of different sites can't be compared, the latest stored question comes first
+        _ => "q.question_id desc",
+    };
+    let tags = split_tags(&query.tag);
+    let match_all = query.tag_mode == "and";
+
+    let statement = client
+        .prepare(&format!(
+            "select q.question_id, q.title, q.q_description, q.question_link, q.votes, q.views, q.stack_id, q.answer
+            from question q {} order by {} limit $5 offset $6;",
+            QUESTION_FILTERS, order
+        ))
+        .await?;
+    let offset = (query.page - 1) * query.limit;
+    let questions = client
+        .query(
+            &statement,
+            &[&query.min_votes, &query.unanswered, &tags, &match_all, &query.limit, &offset],
+        )
+        .await?
+        .iter()
+        .map(|row| Questions::from_row_ref(row).unwrap())
+        .collect::<Vec<Questions>>();
+
+    let statement = client
+        .prepare(&format!("select count(*) from question q {};", QUESTION_FILTERS))
+        .await?;
+    let total: i64 = client
+        .query_one(&statement, &[&query.min_votes, &query.unanswered, &tags, &match_all])
+        .await?
+        .get(0);
+
+    Ok((questions, total))
+}
+
+// Escapes a text column before ts_headline wraps the matches, so the snippet is safe html
+fn headline(column: &str, options: &str) -> String {
+    format!(
+        "ts_headline('english', replace(replace(replace({}, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), query, '{}')",
+        column, options
+    )
+}
+
+// Questions matching the search ranked by relevance, with the number of matches across all pages
+pub async fn search_questions(
+    client: &Client,
+    search: &SearchQuery,
+) -> Result<(Vec<SearchResult>, i64), AppError> {
+    let tags = split_tags(&search.tag);
+    let match_all = search.tag_mode == "and";
+    let no_min_votes: Option<i32> = None;
+    let search_filter = "and q.search_vector @@ query";
+
+    let statement = client
+        .prepare(&format!(
+            "select q.question_id, q.title, q.question_link, q.votes, q.views, q.answer,
+                ts_rank_cd(q.search_vector, query) as rank,
+                {} as title_snippet, {} as description_snippet
+            from question q, websearch_to_tsquery('english', $5) as query
+            {} {} order by rank desc, q.votes desc limit $6 offset $7;",
+            headline("q.title", "StartSel=<mark>, StopSel=</mark>, HighlightAll=true"),
+            headline("q.q_description", "StartSel=<mark>, StopSel=</mark>, MaxWords=35, MinWords=15"),
+            QUESTION_FILTERS,
+            search_filter
+        ))
+        .await?;
+    let offset = (search.page - 1) * search.limit;
+    let results = client
+        .query(
+            &statement,
+            &[&no_min_votes, &false, &tags, &match_all, &search.q, &search.limit, &offset],
+        )
+        .await?
+        .iter()
+        .map(|row| SearchResult::from_row_ref(row).unwrap())
+        .collect::<Vec<SearchResult>>();
+
+    let statement = client
+        .prepare(&format!(
+            "select count(*) from question q, websearch_to_tsquery('english', $5) as query {} {};",
+            QUESTION_FILTERS, search_filter
+        ))
+        .await?;
+    let total: i64 = client
+        .query_one(&statement, &[&no_min_votes, &false, &tags, &match_all, &search.q])
+        .await?
+        .get(0);
+
+    Ok((results, total))
+}
+
 pub async fn get_related_question(
     client: &Client,
     tag_id: i32,
@@ -65,8 +365,7 @@ pub async fn create_tag(client: &Client, tag_title: String) -> Result<Tag, AppEr
         .await?;
     client
         .query(&statement, &[&tag_title])
-        .await
-        .expect("Error creating tag")
+        .await?
         .iter()
         .map(|row| Tag::from_row_ref(row).unwrap())
         .collect::<Vec<Tag>>()
@@ -78,31 +377,173 @@ pub async fn create_tag(client: &Client, tag_title: String) -> Result<Tag, AppEr
         })
 }
 
-pub async fn update_tag(client: &Client, tag_id: i32, tag_title: String) -> Result<bool, AppError> {
+fn tag_not_found(tag_id: i32) -> AppError {
+    AppError {
+        cause: None,
+        message: Some(format!("Tag {} was not found", tag_id)),
+        error_type: AppErrorType::NotFoundError,
+    }
+}
+
+// Renaming to the title of another tag is a conflict, merge the tags instead
+pub async fn update_tag(client: &Client, tag_id: i32, tag_title: String) -> Result<(), AppError> {
     let statement = client
         .prepare("update tag set tag_title = $2 where tag_id=$1;")
         .await?;
-    let result = client
-        .execute(&statement, &[&tag_id, &tag_title])
-        .await
-        .expect("Error updating tag");
-    match result {
-        ref updated if *updated == 1 => Ok(true),
-        _ => Ok(false),
+    match client.execute(&statement, &[&tag_id, &tag_title]).await? {
+        0 => Err(tag_not_found(tag_id)),
+        _ => Ok(()),
+    }
+}
+
+// Its question relations are deleted along, the questions stay
+pub async fn delete_tag(client: &Client, tag_id: i32) -> Result<(), AppError> {
+    let statement = client.prepare("delete from tag where tag_id = $1;").await?;
+    match client.execute(&statement, &[&tag_id]).await? {
+        0 => Err(tag_not_found(tag_id)),
+        _ => Ok(()),
     }
 }
 
-// It will create or get tag id
-pub async fn get_tag_id(client: &Client, tag_name: String) -> Result<TagId, AppError> {
+pub async fn get_tag(client: &impl GenericClient, tag_id: i32) -> Result<Tag, AppError> {
+    let statement = client
+        .prepare("select tag_id, tag_title from tag where tag_id = $1;")
+        .await?;
+    client
+        .query_opt(&statement, &[&tag_id])
+        .await?
+        .map(|row| Tag::from_row_ref(&row).unwrap())
+        .ok_or_else(|| tag_not_found(tag_id))
+}
+
+// Re-points the questions of a tag to another one and deletes it, returns how many questions
+// gained the other tag. Questions having both tags keep a single relation. The synonyms of the
+// tag move along and its title becomes a synonym too, so scrapes keep ending up on the other tag
+pub async fn merge_tag(
+    client: &impl GenericClient,
+    tag_id: i32,
+    into_tag_id: i32,
+) -> Result<u64, AppError> {
+    // merging a tag into itself would delete it along with all its relations
+    if tag_id == into_tag_id {
+        return Err(AppError {
+            cause: None,
+            message: Some("A tag can not be merged into itself".to_string()),
+            error_type: AppErrorType::ValidationError,
+        });
+    }
+    let statement = client
+        .prepare("insert into tag_question (tag_id,question_id) select $2, question_id from tag_question where tag_id = $1 on conflict do nothing;")
+        .await?;
+    let moved = client.execute(&statement, &[&tag_id, &into_tag_id]).await?;
+    // a tag can't be a synonym of itself
+    let statement = client
+        .prepare("delete from tag_synonym where tag_id = $1 and lower(synonym) = (select lower(tag_title) from tag where tag_id = $2);")
+        .await?;
+    client.execute(&statement, &[&tag_id, &into_tag_id]).await?;
+    let statement = client
+        .prepare("update tag_synonym set tag_id = $2 where tag_id = $1;")
+        .await?;
+    client.execute(&statement, &[&tag_id, &into_tag_id]).await?;
     let statement = client
-        .prepare("with s as (select tag_id from tag where tag_title = $1), i as (insert into tag (tag_title) select $1 where not exists (select 1 from s) returning tag_id) select tag_id from i union all select tag_id from s;")
+        .prepare("insert into tag_synonym (synonym,tag_id) select tag_title, $2 from tag where tag_id = $1 on conflict do nothing;")
+        .await?;
+    client.execute(&statement, &[&tag_id, &into_tag_id]).await?;
+    let statement = client.prepare("delete from tag where tag_id = $1;").await?;
+    client.execute(&statement, &[&tag_id]).await?;
+    Ok(moved)
+}
+
+pub async fn get_tag_synonyms(client: &Client) -> Result<Vec<TagSynonym>, AppError> {
+    let statement = client
+        .prepare("select s.synonym_id, s.synonym, s.tag_id, t.tag_title from tag_synonym s, tag t where s.tag_id = t.tag_id order by t.tag_title, s.synonym;")
+        .await?;
+    let synonyms = client
+        .query(&statement, &[])
+        .await?
+        .iter()
+        .map(|row| TagSynonym::from_row_ref(row).unwrap())
+        .collect::<Vec<TagSynonym>>();
+
+    Ok(synonyms)
+}
+
+// A synonym already taken by another tag is a conflict
+pub async fn create_tag_synonym(
+    client: &impl GenericClient,
+    synonym: &CreateTagSynonym,
+) -> Result<TagSynonym, AppError> {
+    let tag = get_tag(client, synonym.tag_id).await?;
+    if tag.tag_title.to_lowercase() == synonym.synonym.trim().to_lowercase() {
+        return Err(AppError {
+            cause: None,
+            message: Some(format!("{} is the title of the tag itself", tag.tag_title)),
+            error_type: AppErrorType::ValidationError,
+        });
+    }
+    let statement = client
+        .prepare("insert into tag_synonym (synonym,tag_id) values ($1,$2) returning synonym_id;")
+        .await?;
+    let synonym_id = client
+        .query_one(&statement, &[&synonym.synonym.trim(), &tag.tag_id])
+        .await?
+        .get("synonym_id");
+
+    Ok(TagSynonym {
+        synonym_id,
+        synonym: synonym.synonym.trim().to_string(),
+        tag_id: tag.tag_id,
+        tag_title: tag.tag_title,
+    })
+}
+
+pub async fn delete_tag_synonym(client: &Client, synonym_id: i32) -> Result<(), AppError> {
+    let statement = client
+        .prepare("delete from tag_synonym where synonym_id = $1;")
+        .await?;
+    match client.execute(&statement, &[&synonym_id]).await? {
+        0 => Err(AppError {
+            cause: None,
+            message: Some(format!("Tag synonym {} was not found", synonym_id)),
+            error_type: AppErrorType::NotFoundError,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Merges the tags created before a synonym of their title existed into the tag of the synonym
+pub async fn collapse_synonym_tags(
+    client: &impl GenericClient,
+) -> Result<Vec<CollapsedTag>, AppError> {
+    let statement = client
+        .prepare("select t.tag_id, t.tag_title, s.tag_id as into_tag_id from tag t, tag_synonym s where lower(s.synonym) = lower(t.tag_title) and s.tag_id <> t.tag_id;")
+        .await?;
+    let duplicates = client.query(&statement, &[]).await?;
+
+    let mut collapsed = Vec::new();
+    for row in duplicates {
+        let into_tag_id: i32 = row.get("into_tag_id");
+        let questions_moved = merge_tag(client, row.get("tag_id"), into_tag_id).await?;
+        collapsed.push(CollapsedTag {
+            tag_title: row.get("tag_title"),
+            into_tag_id,
+            questions_moved,
+        });
+    }
+    Ok(collapsed)
+}
+
+// It will create or get tag id. A synonym of the name wins over a tag of that name, the case is ignored
+pub async fn get_tag_id(client: &impl GenericClient, tag_name: String) -> Result<TagId, AppError> {
+    let statement = client
+        .prepare("with s as ((select tag_id from tag_synonym where lower(synonym) = lower($1)) union all (select tag_id from tag where lower(tag_title) = lower($1)) limit 1),
+            i as (insert into tag (tag_title) select $1 where not exists (select 1 from s) returning tag_id)
+            select tag_id, true as created from i union all select tag_id, false as created from s;")
         .await?;
-    println!("tag name {:?}",tag_name);
 
     client
         .query(&statement, &[&tag_name])
-        .await
-        .expect("Error creating tag")
+        .await?
         .iter()
         .map(|row| TagId::from_row_ref(row).unwrap())
         .collect::<Vec<TagId>>()
@@ -114,13 +555,19 @@ pub async fn get_tag_id(client: &Client, tag_name: String) -> Result<TagId, AppE
         })
 }
 
-pub async fn create_or_skip(
-    client: &Client,
+// Inserts a scraped question of `site` or refreshes the statistics of the stored one with its
+// stack id. The question id is returned either way, `inserted` tells which of both happened
+pub async fn upsert_question(
+    client: &impl GenericClient,
+    site: &str,
     question: &ScrapedQuestion,
-) -> Result<QuestionId, AppError> {
+) -> Result<UpsertedQuestion, AppError> {
     let statement = client
         .prepare(
-            "insert into question (title,q_description,question_link,votes,stack_id,views,answer) values ($1,$2,$3,$4,$5,$6,$7) on conflict (stack_id) do nothing returning question_id")
+            "insert into question (title,q_description,question_link,votes,stack_id,views,answer,site) values ($1,$2,$3,$4,$5,$6,$7,$8)
+            on conflict (site,stack_id) do update set votes = excluded.votes, views = excluded.views, answer = excluded.answer,
+            question_link = coalesce(question.question_link, excluded.question_link)
+            returning question_id, stack_id, (xmax = 0) as inserted")
         .await?;
     client
         .query(
@@ -133,33 +580,972 @@ pub async fn create_or_skip(
                 &question.stack_id,
                 &question.views,
                 &question.answer,
+                &site,
             ],
         )
-        .await
-        .expect("Error creating tag")
+        .await?
         .iter()
-        .map(|row| QuestionId::from_row_ref(row).unwrap())
-        .collect::<Vec<QuestionId>>()
+        .map(|row| UpsertedQuestion::from_row_ref(row).unwrap())
+        .collect::<Vec<UpsertedQuestion>>()
         .pop()
         .ok_or(AppError {
             cause: Some("Unknown error".to_string()),
-            message: Some("Error creating todolist".to_string()),
+            message: Some("Error storing question".to_string()),
             error_type: AppErrorType::DbError,
         })
 }
-pub async fn create_tag_quest_rel(
+
+// Stack ids of the questions already stored for `site`, used to stop walking the listing pages early
+pub async fn get_stack_ids(client: &Client, site: &str) -> Result<HashSet<i32>, AppError> {
+    let statement = client
+        .prepare("select stack_id from question where site = $1 and stack_id is not null;")
+        .await?;
+    let stack_ids = client
+        .query(&statement, &[&site])
+        .await?
+        .iter()
+        .map(|row| row.get::<_, i32>("stack_id"))
+        .collect::<HashSet<i32>>();
+
+    Ok(stack_ids)
+}
+
+// Makes the tags of a question exactly `tag_ids`, returns how many relations were added and removed
+pub async fn reconcile_question_tags(
+    client: &impl GenericClient,
+    question_id: i32,
+    tag_ids: &[i32],
+) -> Result<(u64, u64), AppError> {
+    let statement = client
+        .prepare("delete from tag_question where question_id = $1 and not (tag_id = any($2));")
+        .await?;
+    let removed = client.execute(&statement, &[&question_id, &tag_ids]).await?;
+
+    let statement = client
+        .prepare("insert into tag_question (tag_id,question_id) select tag_id, $1 from unnest($2::integer[]) as tag_id on conflict do nothing;")
+        .await?;
+    let added = client.execute(&statement, &[&question_id, &tag_ids]).await?;
+
+    Ok((added, removed))
+}
+
+// Stores a batch of scraped questions with a handful of statements instead of a few round trips
+// per question: every column is sent as an array and expanded with unnest. Does the same as
+// upsert_question, record_snapshot, get_tag_id and reconcile_question_tags for each question
+pub async fn bulk_store_questions(
+    client: &impl GenericClient,
+    site: &str,
+    questions: &[ScrapedQuestion],
+) -> Result<BulkStored, AppError> {
+    // a row can only be upserted once per statement, the last scrape of a question wins
+    let mut seen = HashSet::new();
+    let mut questions = questions
+        .iter()
+        .rev()
+        .filter(|question| seen.insert(question.stack_id))
+        .collect::<Vec<&ScrapedQuestion>>();
+    questions.reverse();
+
+    let statement = client
+        .prepare(
+            "insert into question (title,q_description,question_link,votes,stack_id,views,answer,site)
+            select *, $8::varchar from unnest($1::varchar[],$2::varchar[],$3::varchar[],$4::integer[],$5::integer[],$6::integer[],$7::integer[])
+            on conflict (site,stack_id) do update set votes = excluded.votes, views = excluded.views, answer = excluded.answer,
+            question_link = coalesce(question.question_link, excluded.question_link)
+            returning question_id, stack_id, (xmax = 0) as inserted",
+        )
+        .await?;
+    let titles: Vec<&str> = questions.iter().map(|q| q.title.as_str()).collect();
+    let descriptions: Vec<&str> = questions.iter().map(|q| q.q_description.as_str()).collect();
+    let links: Vec<&str> = questions.iter().map(|q| q.question_link.as_str()).collect();
+    let votes: Vec<i32> = questions.iter().map(|q| q.votes).collect();
+    let stack_ids: Vec<i32> = questions.iter().map(|q| q.stack_id).collect();
+    let views: Vec<i32> = questions.iter().map(|q| q.views).collect();
+    let answers: Vec<i32> = questions.iter().map(|q| q.answer).collect();
+    let upserted = client
+        .query(
+            &statement,
+            &[&titles, &descriptions, &links, &votes, &stack_ids, &views, &answers, &site],
+        )
+        .await?
+        .iter()
+        .map(|row| UpsertedQuestion::from_row_ref(row).unwrap())
+        .collect::<Vec<UpsertedQuestion>>();
+    let question_ids: HashMap<i32, i32> = upserted
+        .iter()
+        .map(|question| (question.stack_id, question.question_id))
+        .collect();
+    let ordered_ids: Vec<i32> = stack_ids.iter().map(|stack_id| question_ids[stack_id]).collect();
+
+    let statement = client
+        .prepare(
+            "insert into question_snapshot (question_id,votes,answer,views)
+            select * from unnest($1::integer[],$2::integer[],$3::integer[],$4::integer[]);",
+        )
+        .await?;
+    client
+        .execute(&statement, &[&ordered_ids, &votes, &answers, &views])
+        .await?;
+
+    let tag_titles: Vec<&str> = questions
+        .iter()
+        .flat_map(|q| q.tags.iter().map(String::as_str))
+        .collect::<HashSet<&str>>()
+        .into_iter()
+        .collect();
+    // titles are resolved like get_tag_id does, through the synonyms and ignoring the case
+    let statement = client
+        .prepare(
+            "insert into tag (tag_title) select title from unnest($1::varchar[]) as title
+            where not exists (select 1 from tag_synonym s where lower(s.synonym) = lower(title))
+            on conflict do nothing;",
+        )
+        .await?;
+    let tags_created = client.execute(&statement, &[&tag_titles]).await?;
+    let statement = client
+        .prepare(
+            "select title as tag_title, coalesce(s.tag_id, t.tag_id) as tag_id from unnest($1::varchar[]) as title
+            left join tag_synonym s on lower(s.synonym) = lower(title)
+            left join tag t on lower(t.tag_title) = lower(title)
+            where coalesce(s.tag_id, t.tag_id) is not null;",
+        )
+        .await?;
+    let tag_ids: HashMap<String, i32> = client
+        .query(&statement, &[&tag_titles])
+        .await?
+        .iter()
+        .map(|row| Tag::from_row_ref(row).unwrap())
+        .map(|tag| (tag.tag_title, tag.tag_id))
+        .collect();
+
+    let mut relation_questions = Vec::new();
+    let mut relation_tags = Vec::new();
+    for (question, question_id) in questions.iter().zip(&ordered_ids) {
+        for tag in &question.tags {
+            // the caller falls back to storing the questions one by one
+            let tag_id = tag_ids.get(tag).ok_or_else(|| AppError {
+                cause: Some(format!("tag {:?} was not found after inserting it", tag)),
+                message: None,
+                error_type: AppErrorType::DbError,
+            })?;
+            relation_questions.push(*question_id);
+            relation_tags.push(*tag_id);
+        }
+    }
+    let statement = client
+        .prepare(
+            "delete from tag_question tq where tq.question_id = any($1)
+            and not exists (select 1 from unnest($2::integer[],$3::integer[]) as fresh (question_id, tag_id)
+                where fresh.question_id = tq.question_id and fresh.tag_id = tq.tag_id);",
+        )
+        .await?;
+    let tags_removed = client
+        .execute(&statement, &[&ordered_ids, &relation_questions, &relation_tags])
+        .await?;
+    let statement = client
+        .prepare(
+            "insert into tag_question (question_id,tag_id)
+            select * from unnest($1::integer[],$2::integer[]) on conflict do nothing;",
+        )
+        .await?;
+    let tags_added = client
+        .execute(&statement, &[&relation_questions, &relation_tags])
+        .await?;
+
+    Ok(BulkStored {
+        questions: upserted,
+        tags_added,
+        tags_removed,
+        tags_created,
+    })
+}
+
+pub async fn get_scrape_targets(client: &Client) -> Result<Vec<ScrapeTarget>, AppError> {
+    let statement = client
+        .prepare("select * from scrape_target order by target_id;")
+        .await?;
+    let targets = client
+        .query(&statement, &[])
+        .await?
+        .iter()
+        .map(|row| ScrapeTarget::from_row_ref(row).unwrap())
+        .collect::<Vec<ScrapeTarget>>();
+
+    Ok(targets)
+}
+
+// Only the enabled targets take part in the scheduled scraping
+pub async fn get_enabled_scrape_targets(client: &Client) -> Result<Vec<ScrapeTarget>, AppError> {
+    let statement = client
+        .prepare("select * from scrape_target where enabled order by target_id;")
+        .await?;
+    let targets = client
+        .query(&statement, &[])
+        .await?
+        .iter()
+        .map(|row| ScrapeTarget::from_row_ref(row).unwrap())
+        .collect::<Vec<ScrapeTarget>>();
+
+    Ok(targets)
+}
+
+pub async fn create_scrape_target(
     client: &Client,
-    question: &TagQuestion,
-) -> Result<bool, AppError> {
+    target: &CreateScrapeTarget,
+) -> Result<ScrapeTarget, AppError> {
     let statement = client
-        .prepare("insert into tag_question (tag_id,question_id) values ($1,$2);")
+        .prepare("insert into scrape_target (tag_title,tab,weight,enabled,source,site,dump_path) values ($1,$2,$3,$4,$5,$6,$7) returning *;")
+        .await?;
+    client
+        .query(
+            &statement,
+            &[
+                &target.tag_title,
+                &target.tab,
+                &target.weight,
+                &target.enabled,
+                &target.source,
+                &target.site,
+                &target.dump_path,
+            ],
+        )
+        .await?
+        .iter()
+        .map(|row| ScrapeTarget::from_row_ref(row).unwrap())
+        .collect::<Vec<ScrapeTarget>>()
+        .pop()
+        .ok_or(AppError {
+            cause: Some("Unknown error".to_string()),
+            message: Some("Error creating scrape target".to_string()),
+            error_type: AppErrorType::DbError,
+        })
+}
+
+pub async fn update_scrape_target(client: &Client, target: &ScrapeTarget) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update scrape_target set tag_title = $2, tab = $3, weight = $4, enabled = $5, source = $6, site = $7, dump_path = $8 where target_id = $1;")
         .await?;
     let result = client
-        .execute(&statement, &[&question.tag_id, &question.question_id])
-        .await
-        .expect("Error updating tag");
+        .execute(
+            &statement,
+            &[
+                &target.target_id,
+                &target.tag_title,
+                &target.tab,
+                &target.weight,
+                &target.enabled,
+                &target.source,
+                &target.site,
+                &target.dump_path,
+            ],
+        )
+        .await?;
     match result {
-        ref updated if *updated == 1 => Ok(true),
-        _ => Ok(false),
+        0 => Err(AppError {
+            cause: None,
+            message: Some(format!("Scrape target {} was not found", target.target_id)),
+            error_type: AppErrorType::NotFoundError,
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_scrape_target(client: &Client, target_id: i32) -> Result<(), AppError> {
+    let statement = client
+        .prepare("delete from scrape_target where target_id = $1;")
+        .await?;
+    let result = client.execute(&statement, &[&target_id]).await?;
+    match result {
+        0 => Err(AppError {
+            cause: None,
+            message: Some(format!("Scrape target {} was not found", target_id)),
+            error_type: AppErrorType::NotFoundError,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Records the start of a scrape run, it is finished by finish_scrape_run or fail_scrape_run.
+// Runs of on-demand scrape jobs have no target
+pub async fn start_scrape_run(client: &Client, target_id: Option<i32>, url: &str) -> Result<i32, AppError> {
+    let statement = client
+        .prepare("insert into scrape_run (target_id,url) values ($1,$2) returning run_id;")
+        .await?;
+    let row = client.query_one(&statement, &[&target_id, &url]).await?;
+    Ok(row.get("run_id"))
+}
+
+// The skipped and rolled back posts are recorded as the errors of a succeeded run
+pub async fn finish_scrape_run(client: &Client, summary: &ScrapeSummary) -> Result<(), AppError> {
+    let errors: Vec<String> = summary
+        .skipped
+        .iter()
+        .chain(&summary.rolled_back)
+        .map(|post| match &post.post_id {
+            Some(post_id) => format!("{}: {}", post_id, post.reason),
+            None => post.reason.clone(),
+        })
+        .collect();
+    let statement = client
+        .prepare(
+            "update scrape_run set status = 'succeeded', finished_at = now(), questions_seen = $2, questions_new = $3,
+            questions_updated = $4, tags_created = $5, details_stored = $6, skipped = $7, rolled_back = $8, errors = $9
+            where run_id = $1;",
+        )
+        .await?;
+    client
+        .execute(
+            &statement,
+            &[
+                &summary.run_id,
+                &(summary.questions_seen as i32),
+                &(summary.questions_new as i32),
+                &(summary.questions_updated as i32),
+                &(summary.tags_created as i32),
+                &(summary.details_stored as i32),
+                &(summary.skipped.len() as i32),
+                &(summary.rolled_back.len() as i32),
+                &errors,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn fail_scrape_run(client: &Client, run_id: i32, error: &str) -> Result<(), AppError> {
+    let statement = client
+        .prepare(
+            "update scrape_run set status = 'failed', finished_at = now(), errors = array_append(errors, $2)
+            where run_id = $1 and status = 'running';",
+        )
+        .await?;
+    client.execute(&statement, &[&run_id, &error]).await?;
+    Ok(())
+}
+
+// Latest runs first
+pub async fn get_scrape_runs(client: &Client, limit: i64) -> Result<Vec<ScrapeRun>, AppError> {
+    let statement = client
+        .prepare("select * from scrape_run order by started_at desc, run_id desc limit $1;")
+        .await?;
+    let runs = client
+        .query(&statement, &[&limit])
+        .await?
+        .iter()
+        .map(|row| ScrapeRun::from_row_ref(row).unwrap())
+        .collect::<Vec<ScrapeRun>>();
+
+    Ok(runs)
+}
+
+const SCRAPE_JOB_SELECT: &str = "select j.*, r.questions_seen, r.questions_new, r.questions_updated from scrape_job j
+    left join scrape_run r on r.run_id = j.run_id and r.status <> 'running'";
+
+pub async fn create_scrape_job(client: &Client, job: &CreateScrapeJob) -> Result<ScrapeJob, AppError> {
+    let statement = client
+        .prepare("insert into scrape_job (tag_title,tab,site,source,pages) values ($1,$2,$3,$4,$5) returning job_id;")
+        .await?;
+    let row = client
+        .query_one(
+            &statement,
+            &[&job.tag_title, &job.tab, &job.site, &job.source, &job.pages],
+        )
+        .await?;
+    get_scrape_job(client, row.get("job_id")).await
+}
+
+pub async fn get_scrape_job(client: &Client, job_id: i32) -> Result<ScrapeJob, AppError> {
+    let statement = client
+        .prepare(&format!("{} where j.job_id = $1;", SCRAPE_JOB_SELECT))
+        .await?;
+    client
+        .query_opt(&statement, &[&job_id])
+        .await?
+        .map(|row| ScrapeJob::from_row_ref(&row).unwrap())
+        .ok_or(AppError {
+            cause: None,
+            message: Some(format!("Scrape job {} was not found", job_id)),
+            error_type: AppErrorType::NotFoundError,
+        })
+}
+
+// Leases the oldest queued job to `worker` for `lease_secs`, or a running one whose lease ran out.
+// Skipping locked rows keeps two workers from taking the same job. A job taken over still has the
+// run of its previous worker
+pub async fn claim_scrape_job(client: &Client, worker: &str, lease_secs: f64) -> Result<Option<ScrapeJob>, AppError> {
+    let statement = client
+        .prepare(
+            "update scrape_job set status = 'running', started_at = now(), pages_done = 0, locked_by = $1,
+            locked_until = now() + make_interval(secs => $2)
+            where job_id = (
+                select job_id from scrape_job where status = 'queued' or (status = 'running' and locked_until < now())
+                order by job_id limit 1 for update skip locked
+            ) returning job_id;",
+        )
+        .await?;
+    match client.query_opt(&statement, &[&worker, &lease_secs]).await? {
+        Some(row) => get_scrape_job(client, row.get("job_id")).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+// The updates of a job are only applied while `worker` holds its lease, a worker which lost the
+// job to another one changes nothing
+pub async fn set_scrape_job_run(client: &Client, job_id: i32, worker: &str, run_id: i32) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update scrape_job set run_id = $3 where job_id = $1 and status = 'running' and locked_by = $2;")
+        .await?;
+    client.execute(&statement, &[&job_id, &worker, &run_id]).await?;
+    Ok(())
+}
+
+pub async fn set_scrape_job_progress(
+    client: &Client,
+    job_id: i32,
+    worker: &str,
+    pages_done: i32,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update scrape_job set pages_done = $3 where job_id = $1 and status = 'running' and locked_by = $2;")
+        .await?;
+    client.execute(&statement, &[&job_id, &worker, &pages_done]).await?;
+    Ok(())
+}
+
+// Extends the lease of a running job by `lease_secs` from now
+pub async fn renew_scrape_job(client: &Client, job_id: i32, worker: &str, lease_secs: f64) -> Result<(), AppError> {
+    let statement = client
+        .prepare(
+            "update scrape_job set locked_until = now() + make_interval(secs => $3)
+            where job_id = $1 and status = 'running' and locked_by = $2;",
+        )
+        .await?;
+    client.execute(&statement, &[&job_id, &worker, &lease_secs]).await?;
+    Ok(())
+}
+
+// A job without an error succeeded
+pub async fn finish_scrape_job(
+    client: &Client,
+    job_id: i32,
+    worker: &str,
+    error: Option<&str>,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare(
+            "update scrape_job set status = case when $3::text is null then 'succeeded' else 'failed' end,
+            error = $3, finished_at = now(), locked_until = null
+            where job_id = $1 and status = 'running' and locked_by = $2;",
+        )
+        .await?;
+    client.execute(&statement, &[&job_id, &worker, &error]).await?;
+    Ok(())
+}
+
+// Stores the full body of a question with its answers, answers seen before get their score refreshed.
+// Answers belong to the site of their question
+pub async fn save_question_detail(
+    client: &impl GenericClient,
+    question_id: i32,
+    detail: &QuestionDetail,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare("update question set body_html = $2 where question_id = $1;")
+        .await?;
+    client
+        .execute(&statement, &[&question_id, &detail.body_html])
+        .await?;
+
+    let statement = client
+        .prepare("insert into answer (question_id,stack_answer_id,body_html,score,is_accepted,site) select $1::integer,$2::integer,$3::text,$4::integer,$5::boolean,site from question where question_id = $1 on conflict (site,stack_answer_id) do update set body_html = excluded.body_html, score = excluded.score, is_accepted = excluded.is_accepted;")
+        .await?;
+    for answer in &detail.answers {
+        client
+            .execute(
+                &statement,
+                &[
+                    &question_id,
+                    &answer.stack_answer_id,
+                    &answer.body_html,
+                    &answer.score,
+                    &answer.is_accepted,
+                ],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// Records the statistics a scrape saw for a question
+pub async fn record_snapshot(
+    client: &impl GenericClient,
+    question_id: i32,
+    question: &ScrapedQuestion,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare("insert into question_snapshot (question_id,votes,answer,views) values ($1,$2,$3,$4);")
+        .await?;
+    client
+        .execute(
+            &statement,
+            &[&question_id, &question.votes, &question.answer, &question.views],
+        )
+        .await?;
+    Ok(())
+}
+
+// Snapshots of a question, oldest first
+pub async fn get_question_history(
+    client: &Client,
+    question_id: i32,
+) -> Result<Vec<QuestionSnapshot>, AppError> {
+    let statement = client
+        .prepare("select 1 from question where question_id = $1;")
+        .await?;
+    if client.query_opt(&statement, &[&question_id]).await?.is_none() {
+        return Err(question_not_found(question_id));
+    }
+
+    let statement = client
+        .prepare("select votes,answer,views,observed_at from question_snapshot where question_id = $1 order by observed_at;")
+        .await?;
+    let snapshots = client
+        .query(&statement, &[&question_id])
+        .await?
+        .iter()
+        .map(|row| QuestionSnapshot::from_row_ref(row).unwrap())
+        .collect::<Vec<QuestionSnapshot>>();
+
+    Ok(snapshots)
+}
+
+// Questions which gained the most votes, then views, between their first and last snapshot of
+// the last `hours`. Questions seen only once in the window have nothing to compare and are left out
+pub async fn get_rising_questions(
+    client: &Client,
+    hours: i32,
+    limit: i64,
+) -> Result<Vec<RisingQuestion>, AppError> {
+    let statement = client
+        .prepare(
+            "with observed as (
+                select question_id, votes, answer, views, observed_at,
+                    row_number() over (partition by question_id order by observed_at) as first_rank,
+                    row_number() over (partition by question_id order by observed_at desc) as last_rank
+                from question_snapshot
+                where observed_at >= now() - make_interval(hours => $1)
+            )
+            select q.question_id, q.title, q.question_link, q.votes, q.answer, q.views,
+                l.votes - f.votes as votes_gained, l.answer - f.answer as answers_gained, l.views - f.views as views_gained,
+                f.observed_at as first_observed_at, l.observed_at as last_observed_at
+            from observed f
+            join observed l on l.question_id = f.question_id and l.last_rank = 1
+            join question q on q.question_id = f.question_id
+            where f.first_rank = 1 and l.observed_at > f.observed_at
+            order by votes_gained desc, views_gained desc
+            limit $2;",
+        )
+        .await?;
+    let questions = client
+        .query(&statement, &[&hours, &limit])
+        .await?
+        .iter()
+        .map(RisingQuestion::from_row_ref)
+        .collect::<Result<Vec<RisingQuestion>, _>>()?;
+
+    Ok(questions)
+}
+
+pub async fn enqueue_job(
+    client: &Client,
+    kind: &str,
+    payload: &serde_json::Value,
+    max_attempts: i32,
+) -> Result<Job, AppError> {
+    let statement = client
+        .prepare("insert into jobs (kind,payload,max_attempts) values ($1,$2,$3) returning *;")
+        .await?;
+    let row = client.query_one(&statement, &[&kind, payload, &max_attempts]).await?;
+    Ok(Job::from_row_ref(&row).unwrap())
+}
+
+// Takes the job of one of `kinds` which is due longest. Running jobs whose worker has not finished
+// them within their visibility timeout are taken again, skipping locked rows keeps two workers
+// from taking the same job
+pub async fn claim_job(
+    client: &Client,
+    kinds: &[&str],
+    worker: &str,
+    visibility_timeout_secs: f64,
+) -> Result<Option<Job>, AppError> {
+    let statement = client
+        .prepare(
+            "update jobs set status = 'running', attempts = attempts + 1, locked_by = $2,
+            locked_until = now() + make_interval(secs => $3),
+            last_error = case when status = 'running' then 'visibility timeout' else last_error end
+            where job_id = (
+                select job_id from jobs where kind = any($1) and attempts < max_attempts
+                and ((status = 'queued' and run_at <= now()) or (status = 'running' and locked_until < now()))
+                order by run_at, job_id limit 1 for update skip locked
+            ) returning *;",
+        )
+        .await?;
+    let job = client
+        .query_opt(&statement, &[&kinds, &worker, &visibility_timeout_secs])
+        .await?
+        .map(|row| Job::from_row_ref(&row).unwrap());
+    Ok(job)
+}
+
+// Running jobs past their visibility timeout without any attempts left are dead
+pub async fn bury_timed_out_jobs(client: &Client) -> Result<u64, AppError> {
+    let statement = client
+        .prepare(
+            "update jobs set status = 'dead', locked_until = null, finished_at = now(), last_error = 'visibility timeout'
+            where status = 'running' and locked_until < now() and attempts >= max_attempts;",
+        )
+        .await?;
+    Ok(client.execute(&statement, &[]).await?)
+}
+
+// The updates of a worker are ignored once its job was taken by another worker
+pub async fn complete_job(client: &Client, job_id: i64, worker: &str) -> Result<(), AppError> {
+    let statement = client
+        .prepare(
+            "update jobs set status = 'succeeded', locked_until = null, finished_at = now()
+            where job_id = $1 and status = 'running' and locked_by = $2;",
+        )
+        .await?;
+    client.execute(&statement, &[&job_id, &worker]).await?;
+    Ok(())
+}
+
+// Queues the job again after `retry_in` seconds, without a retry it is dead
+pub async fn fail_job(
+    client: &Client,
+    job_id: i64,
+    worker: &str,
+    error: &str,
+    retry_in: Option<f64>,
+) -> Result<(), AppError> {
+    let statement = client
+        .prepare(
+            "update jobs set status = case when $4::float8 is null then 'dead' else 'queued' end,
+            run_at = now() + make_interval(secs => coalesce($4, 0)), locked_until = null, last_error = $3,
+            finished_at = case when $4::float8 is null then now() end
+            where job_id = $1 and status = 'running' and locked_by = $2;",
+        )
+        .await?;
+    client
+        .execute(&statement, &[&job_id, &worker, &error, &retry_in])
+        .await?;
+    Ok(())
+}
+
+pub async fn get_job(client: &Client, job_id: i64) -> Result<Job, AppError> {
+    let statement = client.prepare("select * from jobs where job_id = $1;").await?;
+    client
+        .query_opt(&statement, &[&job_id])
+        .await?
+        .map(|row| Job::from_row_ref(&row).unwrap())
+        .ok_or(AppError {
+            cause: None,
+            message: Some(format!("Job {} was not found", job_id)),
+            error_type: AppErrorType::NotFoundError,
+        })
+}
+
+// Latest jobs first
+pub async fn get_jobs(client: &Client, query: &JobQuery) -> Result<Vec<Job>, AppError> {
+    let statement = client
+        .prepare(
+            "select * from jobs where ($1::varchar is null or status = $1) and ($2::varchar is null or kind = $2)
+            order by job_id desc limit $3;",
+        )
+        .await?;
+    let jobs = client
+        .query(&statement, &[&query.status, &query.kind, &query.limit])
+        .await?
+        .iter()
+        .map(|row| Job::from_row_ref(row).unwrap())
+        .collect::<Vec<Job>>();
+
+    Ok(jobs)
+}
+
+// Only dead jobs are retried, they get all of their attempts again
+pub async fn retry_job(client: &Client, job_id: i64) -> Result<Job, AppError> {
+    let statement = client
+        .prepare(
+            "update jobs set status = 'queued', attempts = 0, run_at = now(), finished_at = null
+            where job_id = $1 and status = 'dead' returning *;",
+        )
+        .await?;
+    match client.query_opt(&statement, &[&job_id]).await? {
+        Some(row) => Ok(Job::from_row_ref(&row).unwrap()),
+        None => {
+            let job = get_job(client, job_id).await?;
+            Err(AppError {
+                cause: None,
+                message: Some(format!("Job {} is {}, only dead jobs are retried", job_id, job.status)),
+                error_type: AppErrorType::ConflictError,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use deadpool_postgres::Client;
+
+    use super::{
+        bulk_store_questions, bury_timed_out_jobs, claim_job, complete_job, create_tag_synonym,
+        enqueue_job, fail_job, get_job, get_tag_id, merge_tag, reconcile_question_tags,
+        record_snapshot, retry_job, upsert_question,
+    };
+    use crate::config::test_pool;
+    use crate::error::AppErrorType;
+    use crate::models::{CreateTagSynonym, ScrapedQuestion};
+
+    fn bench_questions(first_stack_id: i32, count: i32) -> Vec<ScrapedQuestion> {
+        (0..count)
+            .map(|i| ScrapedQuestion {
+                title: format!("Benchmark question {}", i),
+                q_description: "Generated by the ingestion benchmark".to_string(),
+                question_link: format!("https://stackoverflow.com/questions/{}", first_stack_id + i),
+                votes: i,
+                stack_id: first_stack_id + i,
+                views: i * 10,
+                tags: (0..3).map(|t| format!("bench-tag-{}", (i + t) % 50)).collect(),
+                answer: i % 5,
+            })
+            .collect()
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_merge_tag_keeps_synonyms() {
+        let pool = test_pool();
+        let mut client = pool.get().await.unwrap();
+        let transaction = client.transaction().await.unwrap();
+        let tag_id = get_tag_id(&*transaction, "merge-test-js".to_string()).await.unwrap().tag_id;
+        let into_tag_id = get_tag_id(&*transaction, "merge-test-javascript".to_string()).await.unwrap().tag_id;
+        for synonym in ["merge-test-ecmascript", "Merge-Test-JavaScript"] {
+            let synonym = CreateTagSynonym {
+                synonym: synonym.to_string(),
+                tag_id,
+            };
+            create_tag_synonym(&*transaction, &synonym).await.unwrap();
+        }
+
+        assert!(merge_tag(&*transaction, tag_id, tag_id).await.is_err());
+        merge_tag(&*transaction, tag_id, into_tag_id).await.unwrap();
+
+        for title in ["merge-test-ecmascript", "merge-test-js", "merge-test-javascript"] {
+            let found = get_tag_id(&*transaction, title.to_string()).await.unwrap();
+            assert_eq!(found.tag_id, into_tag_id, "{}", title);
+            assert!(!found.created, "{}", title);
+        }
+        let synonyms: i64 = transaction
+            .query_one("select count(*) from tag_synonym where tag_id = $1;", &[&into_tag_id])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(synonyms, 2);
+        transaction.rollback().await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_stack_ids_are_per_site() {
+        let pool = test_pool();
+        let mut client = pool.get().await.unwrap();
+        let transaction = client.transaction().await.unwrap();
+        let questions = bench_questions(2_000_000_000, 2);
+
+        let stored = bulk_store_questions(&*transaction, "stackoverflow", &questions).await.unwrap();
+        let other = upsert_question(&*transaction, "serverfault", &questions[0]).await.unwrap();
+        let again = upsert_question(&*transaction, "stackoverflow", &questions[0]).await.unwrap();
+
+        assert!(stored.questions.iter().all(|question| question.inserted));
+        assert!(other.inserted);
+        assert_ne!(other.question_id, stored.questions[0].question_id);
+        assert!(!again.inserted);
+        assert_eq!(again.question_id, stored.questions[0].question_id);
+        transaction.rollback().await.unwrap();
+    }
+
+    // Prints its timings, run it with --nocapture
+    // Both paths write inside a transaction which is rolled back afterwards
+    #[actix_rt::test]
+    #[ignore]
+    async fn bulk_ingest_benchmark() {
+        let pool = test_pool();
+        let mut client = pool.get().await.unwrap();
+        let count = 1000;
+
+        let transaction = client.transaction().await.unwrap();
+        let questions = bench_questions(2_000_000_000, count);
+        let started = Instant::now();
+        for question in &questions {
+            let upserted = upsert_question(&*transaction, "stackoverflow", question).await.unwrap();
+            record_snapshot(&*transaction, upserted.question_id, question)
+                .await
+                .unwrap();
+            let mut tag_ids = Vec::new();
+            for tag in &question.tags {
+                tag_ids.push(get_tag_id(&*transaction, tag.clone()).await.unwrap().tag_id);
+            }
+            reconcile_question_tags(&*transaction, upserted.question_id, &tag_ids)
+                .await
+                .unwrap();
+        }
+        let per_row = started.elapsed();
+        transaction.rollback().await.unwrap();
+
+        let transaction = client.transaction().await.unwrap();
+        let questions = bench_questions(2_000_000_000, count);
+        let started = Instant::now();
+        let stored = bulk_store_questions(&*transaction, "stackoverflow", &questions).await.unwrap();
+        let bulk = started.elapsed();
+        transaction.rollback().await.unwrap();
+
+        assert_eq!(stored.questions.len(), count as usize);
+        assert_eq!(stored.tags_added, 3 * count as u64);
+        println!(
+            "per row: {:?} ({:.0} questions/s), bulk: {:?} ({:.0} questions/s)",
+            per_row,
+            count as f64 / per_row.as_secs_f64(),
+            bulk,
+            count as f64 / bulk.as_secs_f64()
+        );
+    }
+
+    // Jobs of a kind of their own, so the tests only ever claim their own jobs
+    fn test_kind() -> String {
+        format!("test-{:08x}", rand::random::<u32>())
+    }
+
+    async fn expire_lock(client: &Client, job_id: i64) {
+        client
+            .execute("update jobs set locked_until = now() - interval '1 second' where job_id = $1;", &[&job_id])
+            .await
+            .unwrap();
+    }
+
+    async fn delete_jobs(client: &Client, kind: &str) {
+        client.execute("delete from jobs where kind = $1;", &[&kind]).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_claim_job_skips_locked_and_reclaims_timed_out_jobs() {
+        let pool = test_pool();
+        let (mut other, client) = (pool.get().await.unwrap(), pool.get().await.unwrap());
+        let kind = test_kind();
+        let kinds = [kind.as_str()];
+        let job = enqueue_job(&client, &kind, &serde_json::json!({}), 3).await.unwrap();
+
+        // a row locked by another worker's claim is skipped instead of waited for
+        let transaction = other.transaction().await.unwrap();
+        transaction
+            .execute("select 1 from jobs where job_id = $1 for update;", &[&job.job_id])
+            .await
+            .unwrap();
+        assert!(claim_job(&client, &kinds, "first", 60.0).await.unwrap().is_none());
+        transaction.rollback().await.unwrap();
+
+        let claimed = claim_job(&client, &kinds, "first", 60.0).await.unwrap().unwrap();
+        assert_eq!((claimed.job_id, claimed.attempts), (job.job_id, 1));
+        assert!(claim_job(&client, &kinds, "second", 60.0).await.unwrap().is_none(), "the job is running");
+
+        expire_lock(&client, job.job_id).await;
+        let reclaimed = claim_job(&client, &kinds, "second", 60.0).await.unwrap().unwrap();
+        assert_eq!(reclaimed.attempts, 2);
+        assert_eq!(reclaimed.locked_by.as_deref(), Some("second"));
+        assert_eq!(reclaimed.last_error.as_deref(), Some("visibility timeout"));
+
+        // the first worker lost the job, its outcome is ignored
+        complete_job(&client, job.job_id, "first").await.unwrap();
+        fail_job(&client, job.job_id, "first", "late failure", None).await.unwrap();
+        assert_eq!(get_job(&client, job.job_id).await.unwrap().status, "running");
+        complete_job(&client, job.job_id, "second").await.unwrap();
+        assert_eq!(get_job(&client, job.job_id).await.unwrap().status, "succeeded");
+
+        delete_jobs(&client, &kind).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_fail_job_retries_until_dead() {
+        let pool = test_pool();
+        let client = pool.get().await.unwrap();
+        let kind = test_kind();
+        let kinds = [kind.as_str()];
+        let job = enqueue_job(&client, &kind, &serde_json::json!({}), 2).await.unwrap();
+
+        claim_job(&client, &kinds, "worker", 60.0).await.unwrap().unwrap();
+        fail_job(&client, job.job_id, "worker", "first failure", Some(60.0)).await.unwrap();
+        let retried = get_job(&client, job.job_id).await.unwrap();
+        assert_eq!(retried.status, "queued");
+        assert_eq!(retried.last_error.as_deref(), Some("first failure"));
+        assert!(claim_job(&client, &kinds, "worker", 60.0).await.unwrap().is_none(), "the retry is not due");
+
+        client
+            .execute("update jobs set run_at = now() where job_id = $1;", &[&job.job_id])
+            .await
+            .unwrap();
+        let claimed = claim_job(&client, &kinds, "worker", 60.0).await.unwrap().unwrap();
+        assert_eq!(claimed.attempts, 2);
+        fail_job(&client, job.job_id, "worker", "last failure", None).await.unwrap();
+        let dead = get_job(&client, job.job_id).await.unwrap();
+        assert_eq!(dead.status, "dead");
+        assert!(dead.finished_at.is_some());
+        assert!(claim_job(&client, &kinds, "worker", 60.0).await.unwrap().is_none());
+
+        delete_jobs(&client, &kind).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_bury_timed_out_jobs() {
+        let pool = test_pool();
+        let client = pool.get().await.unwrap();
+        let kind = test_kind();
+        let kinds = [kind.as_str()];
+        let job = enqueue_job(&client, &kind, &serde_json::json!({}), 1).await.unwrap();
+
+        claim_job(&client, &kinds, "worker", 60.0).await.unwrap().unwrap();
+        expire_lock(&client, job.job_id).await;
+        assert!(claim_job(&client, &kinds, "worker", 60.0).await.unwrap().is_none(), "no attempts are left");
+        assert!(bury_timed_out_jobs(&client).await.unwrap() >= 1);
+        let dead = get_job(&client, job.job_id).await.unwrap();
+        assert_eq!(dead.status, "dead");
+        assert_eq!(dead.last_error.as_deref(), Some("visibility timeout"));
+
+        delete_jobs(&client, &kind).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_retry_job_only_retries_dead_jobs() {
+        let pool = test_pool();
+        let client = pool.get().await.unwrap();
+        let kind = test_kind();
+        let kinds = [kind.as_str()];
+        let job = enqueue_job(&client, &kind, &serde_json::json!({}), 1).await.unwrap();
+
+        let err = retry_job(&client, job.job_id).await.unwrap_err();
+        assert!(matches!(err.error_type, AppErrorType::ConflictError));
+
+        claim_job(&client, &kinds, "worker", 60.0).await.unwrap().unwrap();
+        fail_job(&client, job.job_id, "worker", "failure", None).await.unwrap();
+        let retried = retry_job(&client, job.job_id).await.unwrap();
+        assert_eq!((retried.status.as_str(), retried.attempts), ("queued", 0));
+        assert!(claim_job(&client, &kinds, "worker", 60.0).await.unwrap().is_some());
+
+        delete_jobs(&client, &kind).await;
     }
 }
@@ -17,14 +17,22 @@ use core::fmt;
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use deadpool_postgres::PoolError;
 use serde::Serialize;
+use tokio_postgres::error::SqlState;
 use tokio_postgres::Error;
 use validator::ValidationErrors;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum AppErrorType {
     DbError,
     ValidationError,
     NotFoundError,
+    ScrapeError,
+    // the site asks not to fetch the page, asking again won't change that
+    DisallowedError,
+    ConflictError,
+    // work which didn't finish in the time it was given
+    TimeoutError,
 }
 
 // Struct type is already defined Option<String> and AppErrorType. We can also define later.
@@ -42,7 +50,7 @@ pub struct AppErrorResponse {
 impl AppError {
     // we are handling the none. function name should match field name
     fn message(&self) -> String {
-        match &*self {
+        match self {
             // Error message is found then clone otherwise default message
             AppError {
                 cause: _,
@@ -54,6 +62,11 @@ impl AppError {
                 message: None,
                 error_type: AppErrorType::NotFoundError,
             } => "The requested item was not found".to_string(),
+            AppError {
+                cause: _,
+                message: None,
+                error_type: AppErrorType::ConflictError,
+            } => "The item already exists".to_string(),
             AppError {
                 cause: Some(cause),
                 message: None,
@@ -82,9 +95,12 @@ impl ResponseError for AppError {
 
     fn status_code(&self) -> StatusCode {
         match self.error_type {
-            AppErrorType::DbError => (StatusCode::INTERNAL_SERVER_ERROR),
-            AppErrorType::NotFoundError => (StatusCode::NOT_FOUND),
-            AppErrorType::ValidationError => (StatusCode::LENGTH_REQUIRED),
+            AppErrorType::DbError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppErrorType::NotFoundError => StatusCode::NOT_FOUND,
+            AppErrorType::ValidationError => StatusCode::LENGTH_REQUIRED,
+            AppErrorType::ScrapeError | AppErrorType::DisallowedError => StatusCode::BAD_GATEWAY,
+            AppErrorType::ConflictError => StatusCode::CONFLICT,
+            AppErrorType::TimeoutError => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 
@@ -105,8 +121,30 @@ impl From<PoolError> for AppError {
         }
     }
 }
+// A row which doesn't fit its model, like a null in a column the model expects to be set
+impl From<tokio_pg_mapper::Error> for AppError {
+    fn from(error: tokio_pg_mapper::Error) -> AppError {
+        AppError {
+            message: None,
+            cause: Some(error.to_string()),
+            error_type: AppErrorType::DbError,
+        }
+    }
+}
+// Unique violations are the client's fault, the detail names the duplicated key
 impl From<Error> for AppError {
     fn from(error: Error) -> AppError {
+        if error.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            let detail = error
+                .as_db_error()
+                .and_then(|db_error| db_error.detail())
+                .map(str::to_string);
+            return AppError {
+                message: detail,
+                cause: Some(error.to_string()),
+                error_type: AppErrorType::ConflictError,
+            };
+        }
         AppError {
             message: None,
             cause: Some(error.to_string()),
@@ -124,6 +162,61 @@ impl From<ValidationErrors> for AppError {
     }
 }
 
+impl From<ScrapeError> for AppError {
+    fn from(error: ScrapeError) -> AppError {
+        let error_type = match error {
+            ScrapeError::RobotsDisallowed(_) => AppErrorType::DisallowedError,
+            _ => AppErrorType::ScrapeError,
+        };
+        AppError {
+            message: None,
+            cause: Some(error.to_string()),
+            error_type,
+        }
+    }
+}
+
+// Everything that can go wrong while fetching or parsing a stackoverflow page
+#[derive(Debug)]
+pub enum ScrapeError {
+    // the page could not be fetched
+    Network(String),
+    // a local file could not be read
+    Io(String),
+    // the Stack Exchange API answered with an error
+    Api(String),
+    // robots.txt of the site asks not to fetch the page
+    RobotsDisallowed(String),
+    // an element every post summary should have is missing
+    MissingElement(&'static str),
+    // a field which should be numeric could not be parsed
+    BadNumber { field: &'static str, value: String },
+    // the page no longer looks like the one the selectors were written for
+    LayoutChanged(String),
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScrapeError::Network(cause) => write!(f, "network error: {}", cause),
+            ScrapeError::Io(cause) => write!(f, "io error: {}", cause),
+            ScrapeError::Api(cause) => write!(f, "api error: {}", cause),
+            ScrapeError::RobotsDisallowed(url) => write!(f, "disallowed by robots.txt: {}", url),
+            ScrapeError::MissingElement(element) => write!(f, "missing element: {}", element),
+            ScrapeError::BadNumber { field, value } => {
+                write!(f, "bad number in {}: {:?}", field, value)
+            }
+            ScrapeError::LayoutChanged(cause) => write!(f, "layout changed: {}", cause),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ScrapeError {
+    fn from(error: reqwest::Error) -> ScrapeError {
+        ScrapeError::Network(error.to_string())
+    }
+}
+
 impl From<PoolError> for AppErrorType {
     fn from(_error: PoolError) -> AppErrorType {
         AppErrorType::DbError
@@ -148,7 +241,7 @@ impl ResponseError for AppErrorType {
 #[cfg(test)]
 mod tests {
 
-    use super::{AppError, AppErrorType};
+    use super::{AppError, AppErrorType, ScrapeError};
     use actix_web::error::ResponseError;
 
     #[test]
@@ -216,6 +309,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scrape_error_status_code() {
+        let expected = 502;
+
+        let scrape_error = AppError::from(ScrapeError::MissingElement("post title"));
+
+        assert_eq!(
+            scrape_error.status_code(),
+            expected,
+            "Status code for ScrapeError should be {}",
+            expected
+        );
+    }
+
+    #[test]
+    fn test_conflict_error() {
+        let conflict_error = AppError {
+            message: None,
+            cause: None,
+            error_type: AppErrorType::ConflictError,
+        };
+
+        assert_eq!(conflict_error.status_code(), 409, "Status code for ConflictError should be 409");
+        assert_eq!(
+            conflict_error.message(),
+            "The item already exists".to_string(),
+            "Default message should be shown"
+        );
+    }
+
     #[test]
     fn test_validation_length_status_code() {
         let expected = 411;
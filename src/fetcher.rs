@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use regex::Regex;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode, Url};
+use slog::{info, warn, Logger};
+
+use crate::config::ScraperConfig;
+use crate::error::ScrapeError;
+
+// Longest wait before a retry, whatever the backoff or Retry-After say
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(300);
+
+// Refills `rate` tokens per second up to `capacity`, every request takes one token
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, requests_per_minute: u32) -> TokenBucket {
+        let capacity = f64::from(capacity.max(1));
+        TokenBucket {
+            capacity,
+            rate: f64::from(requests_per_minute.max(1)) / 60.0,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes a token, or tells how long to wait until the next one is available
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+// Allow and Disallow rules of the robots.txt group that applies to us
+#[derive(Debug, Default)]
+pub struct Robots {
+    rules: Vec<(bool, Regex, usize)>,
+}
+
+impl Robots {
+    // Uses the group naming our agent, or the `*` group when there is none
+    pub fn parse(robots_txt: &str, agent: &str) -> Robots {
+        let agent = agent.to_lowercase();
+        let mut ours = Vec::new();
+        let mut wildcard = Vec::new();
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+
+        for line in robots_txt.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+            match field.as_str() {
+                "user-agent" => {
+                    // a user-agent line after rules starts a new group
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // an empty disallow allows everything
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let rule = (field == "allow", value.to_string());
+                    if agents.iter().any(|name| name != "*" && agent.contains(name.as_str())) {
+                        ours.push(rule.clone());
+                    }
+                    if agents.iter().any(|name| name == "*") {
+                        wildcard.push(rule);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let rules = if ours.is_empty() { wildcard } else { ours };
+        Robots {
+            rules: rules
+                .into_iter()
+                .map(|(allow, pattern)| (allow, Robots::pattern(&pattern), pattern.len()))
+                .collect(),
+        }
+    }
+
+    // robots.txt paths are prefixes where `*` matches anything and a trailing `$` anchors the end
+    fn pattern(path: &str) -> Regex {
+        let (path, anchored) = match path.strip_suffix('$') {
+            Some(path) => (path, "$"),
+            None => (path, ""),
+        };
+        let escaped = path
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(".*");
+        Regex::new(&format!("^{}{}", escaped, anchored)).unwrap()
+    }
+
+    // The longest matching rule wins, allow wins a tie
+    pub fn allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, pattern, _)| pattern.is_match(path))
+            .max_by_key(|(allow, _, length)| (*length, *allow))
+            .map(|(allow, _, _)| *allow)
+            .unwrap_or(true)
+    }
+}
+
+// Retry-After holds either the seconds to wait or the date to wait for
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+// The one http client every scrape request goes through. It identifies itself with the
+// configured user agent, throttles requests, retries 429 and 5xx responses and keeps out of
+// the paths robots.txt disallows
+pub struct Fetcher {
+    client: Client,
+    agent: String,
+    bucket: Mutex<TokenBucket>,
+    max_retries: u32,
+    retry_base: Duration,
+    respect_robots: bool,
+    robots: Mutex<HashMap<String, Arc<Robots>>>,
+}
+
+impl Fetcher {
+    pub fn new(config: &ScraperConfig) -> Result<Fetcher, ScrapeError> {
+        let client = Client::builder()
+            .user_agent(config.user_agent.clone())
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()?;
+        Ok(Fetcher {
+            client,
+            // the product token of the user agent is matched against robots.txt groups
+            agent: config.user_agent.split('/').next().unwrap_or_default().to_string(),
+            bucket: Mutex::new(TokenBucket::new(config.burst, config.requests_per_minute)),
+            max_retries: config.max_retries,
+            retry_base: Duration::from_millis(config.retry_base_ms),
+            respect_robots: config.respect_robots,
+            robots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Fetches a web page, failing for anything but a successful response
+    pub async fn get_text(&self, log: &Logger, url: &str) -> Result<String, ScrapeError> {
+        let url = Url::parse(url).map_err(|err| ScrapeError::Network(format!("{}: {}", url, err)))?;
+        // robots.txt rules match the query string too
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        if self.respect_robots && !self.robots_for(log, &url).await.allowed(&path) {
+            return Err(ScrapeError::RobotsDisallowed(url.to_string()));
+        }
+        let resp = self.send(log, &url).await?;
+        if !resp.status().is_success() {
+            return Err(ScrapeError::Network(format!("{} answered {}", url, resp.status())));
+        }
+        Ok(resp.text().await?)
+    }
+
+    // Fetches an API response. APIs aren't crawled, so robots.txt is not consulted, and client
+    // errors are handed back because APIs explain them in the body
+    pub async fn get_api_text(&self, log: &Logger, url: &str) -> Result<String, ScrapeError> {
+        let url = Url::parse(url).map_err(|err| ScrapeError::Network(format!("{}: {}", url, err)))?;
+        Ok(self.send(log, &url).await?.text().await?)
+    }
+
+    async fn throttle(&self) {
+        loop {
+            let wait = match self.bucket.lock().try_take(Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            };
+            actix_rt::time::sleep(wait).await;
+        }
+    }
+
+    // Sends the request, backing off exponentially or as long as Retry-After asks on 429, 5xx
+    // and network failures
+    async fn send(&self, log: &Logger, url: &Url) -> Result<Response, ScrapeError> {
+        let mut attempt = 0;
+        loop {
+            self.throttle().await;
+            let result = self.client.get(url.clone()).send().await;
+            let retry_after = match &result {
+                Ok(resp)
+                    if resp.status() == StatusCode::TOO_MANY_REQUESTS
+                        || resp.status().is_server_error() =>
+                {
+                    resp.headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| parse_retry_after(value, Utc::now()))
+                }
+                Ok(_) => return Ok(result?),
+                Err(_) => None,
+            };
+            if attempt >= self.max_retries {
+                let resp = result?;
+                return Err(ScrapeError::Network(format!(
+                    "{} still answered {} after {} retries",
+                    url,
+                    resp.status(),
+                    attempt
+                )));
+            }
+            let wait = retry_after
+                .unwrap_or_else(|| retry_wait(self.retry_base, attempt))
+                .min(MAX_RETRY_WAIT);
+            match &result {
+                Ok(resp) => warn!(log, "{} answered {}, retrying in {:?}", url, resp.status(), wait),
+                Err(err) => warn!(log, "{} failed with {}, retrying in {:?}", url, err, wait),
+            }
+            actix_rt::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    // robots.txt of the url's site, fetched once per site. A site without one allows everything.
+    // When it can't be fetched the page is allowed, and robots.txt is asked for again next time
+    async fn robots_for(&self, log: &Logger, url: &Url) -> Arc<Robots> {
+        let origin = url.origin().ascii_serialization();
+        if let Some(robots) = self.robots.lock().get(&origin) {
+            return Arc::clone(robots);
+        }
+
+        let robots_url = format!("{}/robots.txt", origin);
+        info!(log, "Fetching robots => {}", &robots_url);
+        let robots = match Url::parse(&robots_url) {
+            Ok(robots_url) => match self.send(log, &robots_url).await {
+                Ok(resp) if resp.status().is_success() => match resp.text().await {
+                    Ok(text) => Robots::parse(&text, &self.agent),
+                    Err(err) => {
+                        warn!(log, "Reading {} failed, not caching it: {}", robots_url, err);
+                        return Arc::new(Robots::default());
+                    }
+                },
+                Ok(resp) if resp.status() == StatusCode::NOT_FOUND => Robots::default(),
+                Ok(resp) => {
+                    warn!(log, "{} answered {}, not caching it", robots_url, resp.status());
+                    return Arc::new(Robots::default());
+                }
+                Err(err) => {
+                    warn!(log, "Fetching {} failed, not caching it: {}", robots_url, err);
+                    return Arc::new(Robots::default());
+                }
+            },
+            Err(_) => Robots::default(),
+        };
+        let robots = Arc::new(robots);
+        self.robots.lock().insert(origin, Arc::clone(&robots));
+        robots
+    }
+}
+
+// Delay before the retry following the given attempt, doubled with every attempt up to the max
+fn retry_wait(base: Duration, attempt: u32) -> Duration {
+    base.checked_mul(2u32.saturating_pow(attempt))
+        .unwrap_or(MAX_RETRY_WAIT)
+        .min(MAX_RETRY_WAIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use chrono::{TimeZone, Utc};
+    use slog::{o, Discard, Logger};
+
+    use super::{parse_retry_after, retry_wait, Fetcher, Robots, TokenBucket, MAX_RETRY_WAIT};
+    use crate::config::ScraperConfig;
+    use crate::error::ScrapeError;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 60);
+
+        assert!(bucket.try_take(start).is_ok());
+        assert!(bucket.try_take(start).is_ok());
+        let wait = bucket.try_take(start).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(
+            bucket.try_take(start + Duration::from_secs(1)).is_ok(),
+            "A token should be refilled after a second"
+        );
+    }
+
+    #[test]
+    fn test_robots() {
+        let robots_txt = "
+            User-agent: *
+            Disallow: /search
+            Disallow: /questions/*answertab=*
+            Allow: /search/help
+            Disallow:
+
+            User-agent: GPTBot
+            User-agent: badbot
+            Disallow: /
+        ";
+
+        let robots = Robots::parse(robots_txt, "actix-question-bank");
+        assert!(robots.allowed("/questions/tagged/rust"));
+        assert!(!robots.allowed("/search?q=rust"));
+        assert!(robots.allowed("/search/help"), "Longer allow rule should win");
+        assert!(!robots.allowed("/questions/42/title?answertab=votes"));
+
+        let robots = Robots::parse(robots_txt, "BadBot");
+        assert!(!robots.allowed("/questions/tagged/rust"), "Our own group should apply");
+    }
+
+    #[test]
+    fn test_robots_end_anchor() {
+        let robots = Robots::parse("User-agent: *\nDisallow: /*.json$", "bot");
+        assert!(!robots.allowed("/data/tags.json"));
+        assert!(robots.allowed("/data/tags.json?page=2"));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.ymd(2015, 10, 21).and_hms(7, 28, 0);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO),
+            "Dates in the past need no wait"
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_retry_wait_doubles_up_to_the_max() {
+        let base = Duration::from_secs(1);
+
+        assert_eq!(retry_wait(base, 0), base);
+        assert_eq!(retry_wait(base, 3), Duration::from_secs(8));
+        assert_eq!(retry_wait(base, 31), MAX_RETRY_WAIT);
+        assert_eq!(retry_wait(base, 32), MAX_RETRY_WAIT);
+        assert_eq!(retry_wait(base, u32::MAX), MAX_RETRY_WAIT);
+    }
+
+    // Answers 429 until `failures` requests were made, robots.txt disallows /private and the
+    // answer tabs of the questions
+    fn mock_site(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let server = HttpServer::new(move || {
+            let counter = Arc::clone(&counter);
+            App::new()
+                .route(
+                    "/robots.txt",
+                    web::get().to(|| async { "User-agent: *\nDisallow: /private\nDisallow: /questions/*?answertab=" }),
+                )
+                .default_service(web::to(move || {
+                    let seen = counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        if seen < failures {
+                            HttpResponse::TooManyRequests()
+                                .insert_header(("Retry-After", "0"))
+                                .finish()
+                        } else {
+                            HttpResponse::Ok().body("page")
+                        }
+                    }
+                }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        (format!("http://{}", address), requests)
+    }
+
+    fn fetcher(max_retries: u32) -> Fetcher {
+        let config = ScraperConfig {
+            max_retries,
+            requests_per_minute: 6000,
+            ..ScraperConfig::default()
+        };
+        Fetcher::new(&config).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_retries_too_many_requests() {
+        let (base, requests) = mock_site(2);
+        let log = Logger::root(Discard, o!());
+
+        let page = fetcher(3).get_text(&log, &format!("{}/questions", base)).await;
+        assert_eq!(page.unwrap(), "page");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_gives_up_after_max_retries() {
+        let (base, requests) = mock_site(5);
+        let log = Logger::root(Discard, o!());
+
+        let page = fetcher(1).get_text(&log, &format!("{}/questions", base)).await;
+        assert!(matches!(page, Err(ScrapeError::Network(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_respects_robots() {
+        let (base, requests) = mock_site(0);
+        let log = Logger::root(Discard, o!());
+
+        let page = fetcher(0).get_text(&log, &format!("{}/private/page", base)).await;
+        assert!(matches!(page, Err(ScrapeError::RobotsDisallowed(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 0, "Disallowed page should not be fetched");
+    }
+
+    #[actix_rt::test]
+    async fn test_respects_robots_query() {
+        let (base, requests) = mock_site(0);
+        let log = Logger::root(Discard, o!());
+        let fetcher = fetcher(0);
+
+        let page = fetcher
+            .get_text(&log, &format!("{}/questions/1/title?answertab=votes", base))
+            .await;
+        assert!(matches!(page, Err(ScrapeError::RobotsDisallowed(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        let page = fetcher.get_text(&log, &format!("{}/questions/1/title", base)).await;
+        assert_eq!(page.unwrap(), "page");
+    }
+
+    // robots.txt fails with 503 for the first `failures` requests, then disallows /private
+    fn flaky_robots_site(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let server = HttpServer::new(move || {
+            let counter = Arc::clone(&counter);
+            App::new()
+                .route(
+                    "/robots.txt",
+                    web::get().to(move || {
+                        let seen = counter.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            if seen < failures {
+                                HttpResponse::ServiceUnavailable().finish()
+                            } else {
+                                HttpResponse::Ok().body("User-agent: *\nDisallow: /private")
+                            }
+                        }
+                    }),
+                )
+                .default_service(web::to(|| async { "page" }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        (format!("http://{}", address), requests)
+    }
+
+    #[actix_rt::test]
+    async fn test_failed_robots_are_fetched_again() {
+        let (base, robots_requests) = flaky_robots_site(1);
+        let log = Logger::root(Discard, o!());
+        let fetcher = fetcher(0);
+        let url = format!("{}/private/page", base);
+
+        assert_eq!(fetcher.get_text(&log, &url).await.unwrap(), "page");
+        let page = fetcher.get_text(&log, &url).await;
+        assert!(matches!(page, Err(ScrapeError::RobotsDisallowed(_))));
+        fetcher.get_text(&log, &url).await.unwrap_err();
+        assert_eq!(robots_requests.load(Ordering::SeqCst), 2, "A fetched robots.txt is cached");
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_robots_allow_everything() {
+        let server = HttpServer::new(|| App::new().route("/page", web::get().to(|| async { "page" })))
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_rt::spawn(server.run());
+        let log = Logger::root(Discard, o!());
+        let fetcher = fetcher(0);
+
+        assert_eq!(fetcher.get_text(&log, &format!("{}/page", base)).await.unwrap(), "page");
+        assert!(fetcher.robots.lock().contains_key(&base), "A missing robots.txt is cached");
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 
 use crate::db;
-use crate::error::AppError;
+use crate::error::{AppError, AppErrorType};
 use crate::models::{
-    AppState, CreateTag, Questions, ResultResponse, ScrapedQuestion, Tag, TagQuestionRelation, TagQuestion,
+    AppState, CreateTag, QuestionDetailJob, QuestionWithTags, Questions, ResultResponse, ScrapeRun, ScrapeRunQuery, ScrapeSummary, ScrapeTarget,
+    ScrapedQuestion, SearchQuery, SearchResult, SkippedPost, Tag, TagQuestionRelation, UpdateTag, UpsertedQuestion,
 };
-use crate::scraper::{get_random_url, hacker_news};
+use crate::config::ScraperConfig;
+use crate::fetcher::Fetcher;
+use crate::scraper::{fetch_question_detail, get_random_target, site_url, PageOptions};
+use crate::source::source_for;
 use actix_web::{web, HttpResponse, Responder};
 use deadpool_postgres::{Client, Pool};
 use sailfish::TemplateOnce;
-use slog::{crit, info, o, Logger};
+use slog::{crit, error, info, o, warn, Logger};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_postgres::GenericClient;
+use validator::Validate;
 
 //  Templates Data
 #[derive(TemplateOnce)]
@@ -28,12 +35,33 @@ struct QuestionTemplate {
     questions_list: Vec<Questions>,
 }
 
+#[derive(TemplateOnce)]
+#[template(path = "question.stpl")]
+struct SingleQuestionTemplate {
+    question: QuestionWithTags,
+}
+
 #[derive(TemplateOnce)]
 #[template(path = "question_by_tag.stpl")]
 struct QuestionByIdTemplate {
     questions_list: Vec<TagQuestionRelation>,
 }
 
+#[derive(TemplateOnce)]
+#[template(path = "search.stpl")]
+struct SearchTemplate {
+    q: String,
+    tag: String,
+    total: i64,
+    results: Vec<SearchResult>,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "scrape_runs.stpl")]
+struct ScrapeRunsTemplate {
+    runs: Vec<ScrapeRun>,
+}
+
 #[derive(TemplateOnce)]
 #[template(path = "create_success.stpl")]
 struct CreateTagTemplate {
@@ -64,58 +92,284 @@ pub async fn get_tags(state: web::Data<AppState>) -> Result<impl Responder, AppE
     })
 }
 
-pub async fn scrape_questions(pool:Pool,log:Logger) -> Result<(), AppError> {
+// Stores a scraped question or refreshes the stored one and records a snapshot of its statistics.
+// Its tag relations are reconciled with the scraped tags. Tags missing from `tag_ids` are looked
+// up or created and collected in `new_tags`. Returns the stored question with the number of
+// relations added and removed and the number of tags created
+async fn store_question(
+    client: &impl GenericClient,
+    site: &str,
+    question: &ScrapedQuestion,
+    tag_ids: &HashMap<String, i32>,
+    new_tags: &mut HashMap<String, i32>,
+) -> Result<(UpsertedQuestion, u64, u64, u64), AppError> {
+    let upserted = db::upsert_question(client, site, question).await?;
+    db::record_snapshot(client, upserted.question_id, question).await?;
+
+    let mut question_tag_ids = Vec::new();
+    let mut created = 0;
+    for tag in &question.tags {
+        let tag_id = match tag_ids.get(tag) {
+            Some(tag_id) => *tag_id,
+            None => {
+                let found = db::get_tag_id(client, tag.clone()).await?;
+                if found.created {
+                    created += 1;
+                }
+                new_tags.insert(tag.clone(), found.tag_id);
+                found.tag_id
+            }
+        };
+        question_tag_ids.push(tag_id);
+    }
+    let (added, removed) =
+        db::reconcile_question_tags(client, upserted.question_id, &question_tag_ids).await?;
+    Ok((upserted, added, removed, created))
+}
+
+// Reason reported for a question whose writes were rolled back
+fn rollback_reason(err: &AppError) -> String {
+    let cause = err.cause.clone().unwrap_or_else(|| err.to_string());
+    format!("rolled back: {}", cause)
+}
+
+pub async fn scrape_questions(
+    pool: Pool,
+    log: Logger,
+    config: &ScraperConfig,
+    fetcher: &Fetcher,
+) -> Result<Option<ScrapeSummary>, AppError> {
     let sublog = log.new(o!("handler" => "scrape_questions"));
-    let client: Client = configure_pool(pool.clone(), sublog.clone()).await?;
-    let url = get_random_url(&log);
-    let mut result = hacker_news(&log, &url, 10).await.unwrap();
-
-    // IT will contains the count of occurence of tag
-    let tags_hashmap = &mut result.unique_tags;
-    let questions = &result.questions;
-
-    // IT contains the tag id as value
-    let mut index_table:HashMap<String, i32> = HashMap::new();
-    
-    for question in questions {
-
-        // creating or getting question from database
-        let question_id_res = db::create_or_skip(&client, &question).await;
-        if let Ok(question_id_res) = question_id_res {
-            let question_id = question_id_res.question_id;
-
-            // iterating over all the tags in a question
-            for tag in &question.tags {
-                if tags_hashmap.get(tag) != Some(&0) {
-                    (*tags_hashmap.entry(tag.clone()).or_insert(0)) -= 1;
-                    let tag_id:i32;
-
-                    // checking id of tag if it exists
-                    if index_table.contains_key(tag){
-                         match index_table.get(tag) {
-                            Some(val)=> {tag_id = val.clone()},
-                            None => {tag_id=-1}
-                         }
-                    } else {
-                        // creating tag
-                        let res = db::get_tag_id(&client, tag.clone()).await.unwrap();
-                        tag_id = res.tag_id as i32;
-                        index_table.insert(tag.to_owned(), tag_id);
-                    }
+    let mut client: Client = configure_pool(pool.clone(), sublog.clone()).await?;
+    let targets = db::get_enabled_scrape_targets(&client).await?;
+    let target = match get_random_target(&log, &targets) {
+        Some(target) => target,
+        None => {
+            info!(sublog, "No enabled scrape targets, skipping run");
+            return Ok(None);
+        }
+    };
+
+    let url = source_for(target, config).location();
+    let run_id = db::start_scrape_run(&client, Some(target.target_id), &url).await?;
+    run_scrape(&mut client, &log, config, fetcher, target, run_id, None)
+        .await
+        .map(Some)
+}
 
-                    // setting relationship btw question and tag
-                    let tag_question = TagQuestion{
-                        tag_id:i32::from(tag_id),
-                        question_id
-                    };
-                    match db::create_tag_quest_rel(&client, &tag_question).await  {
-                        Ok(_)=>println!("created relationship btw tag id {:?} and question id {:?}",tag_id,question_id),
-                        Err(_) => println!("Failed"),
+// Scrapes a target as the started scrape run `run_id` and records how the run ended. The number
+// of every fetched page is sent to `progress`
+pub async fn run_scrape(
+    client: &mut Client,
+    log: &Logger,
+    config: &ScraperConfig,
+    fetcher: &Fetcher,
+    target: &ScrapeTarget,
+    run_id: i32,
+    progress: Option<UnboundedSender<usize>>,
+) -> Result<ScrapeSummary, AppError> {
+    match scrape_target(client, log, config, fetcher, target, run_id, progress).await {
+        Ok(summary) => {
+            db::finish_scrape_run(client, &summary).await?;
+            Ok(summary)
+        }
+        Err(err) => {
+            let cause = err.cause.clone().unwrap_or_else(|| err.to_string());
+            db::fail_scrape_run(client, run_id, &cause).await?;
+            Err(err)
+        }
+    }
+}
+
+// Fetches the questions of one target and stores them
+async fn scrape_target(
+    client: &mut Client,
+    log: &Logger,
+    config: &ScraperConfig,
+    fetcher: &Fetcher,
+    target: &ScrapeTarget,
+    run_id: i32,
+    progress: Option<UnboundedSender<usize>>,
+) -> Result<ScrapeSummary, AppError> {
+    let sublog = log.new(o!("handler" => "scrape_questions", "run_id" => run_id));
+    // Newest questions are listed first, so the first known one means the rest is stored already
+    let known_ids = match target.tab.as_str() {
+        "Newest" if config.stop_at_known => db::get_stack_ids(client, &target.site).await?,
+        _ => HashSet::new(),
+    };
+    let options = PageOptions {
+        total: config.total,
+        max_pages: config.max_pages,
+        known_ids,
+        progress,
+    };
+    let source = source_for(target, config);
+    let result = source.fetch(log, fetcher, &options).await?;
+    let mut skipped = result.skipped;
+    let mut rolled_back = Vec::new();
+
+    // The whole batch is written in one transaction. It is stored in bulk first, when that fails
+    // every question gets a savepoint so a failing question is rolled back alone instead of
+    // leaving it without its tags
+    let mut stored = Vec::new();
+    let (mut questions_updated, mut tags_added, mut tags_removed, mut tags_created) = (0, 0, 0, 0);
+    let mut transaction = client.transaction().await?;
+    let savepoint = transaction.savepoint("scraped_batch").await?;
+    match db::bulk_store_questions(&*savepoint, &target.site, &result.questions).await {
+        Ok(bulk) => {
+            savepoint.commit().await?;
+            tags_added = bulk.tags_added;
+            tags_removed = bulk.tags_removed;
+            tags_created = bulk.tags_created;
+            for upserted in bulk.questions {
+                if upserted.inserted {
+                    // the bulk insert skips repeated stack ids, their last scrape is stored
+                    let question = result
+                        .questions
+                        .iter()
+                        .rev()
+                        .find(|question| question.stack_id == upserted.stack_id)
+                        .unwrap();
+                    stored.push((upserted.question_id, question));
+                } else {
+                    questions_updated += 1;
+                }
+            }
+        }
+        Err(err) => {
+            savepoint.rollback().await?;
+            warn!(sublog, "Bulk insert failed, storing questions one by one: {:?}", err);
+            let mut tag_ids: HashMap<String, i32> = HashMap::new();
+            for question in &result.questions {
+                let savepoint = transaction.savepoint("scraped_question").await?;
+                let mut new_tags = HashMap::new();
+                match store_question(&*savepoint, &target.site, question, &tag_ids, &mut new_tags).await {
+                    Ok((upserted, added, removed, created)) => {
+                        savepoint.commit().await?;
+                        // tag ids are only trusted once the savepoint which created them holds
+                        tag_ids.extend(new_tags);
+                        tags_added += added;
+                        tags_removed += removed;
+                        tags_created += created;
+                        if upserted.inserted {
+                            stored.push((upserted.question_id, question));
+                        } else {
+                            questions_updated += 1;
+                        }
+                    }
+                    Err(err) => {
+                        savepoint.rollback().await?;
+                        warn!(sublog, "Rolled back question {}: {:?}", question.stack_id, err);
+                        rolled_back.push(SkippedPost {
+                            post_id: Some(question.stack_id.to_string()),
+                            reason: rollback_reason(&err),
+                        });
                     }
                 }
             }
         }
     }
+    transaction.commit().await?;
+
+    // second pass for the full body and the answers of the new questions, outside of the batch
+    // transaction so it is not held open while pages are fetched
+    let mut details_stored = 0;
+    if config.fetch_details {
+        for (question_id, question) in &stored {
+            let detail = match fetch_question_detail(log, fetcher, &question.question_link).await {
+                Ok(detail) => detail,
+                Err(err) => {
+                    skipped.push(SkippedPost {
+                        post_id: Some(question.stack_id.to_string()),
+                        reason: format!("question detail: {}", err),
+                    });
+                    continue;
+                }
+            };
+            let transaction = client.transaction().await?;
+            match db::save_question_detail(&*transaction, *question_id, &detail).await {
+                Ok(()) => {
+                    transaction.commit().await?;
+                    details_stored += 1;
+                }
+                Err(err) => {
+                    transaction.rollback().await?;
+                    rolled_back.push(SkippedPost {
+                        post_id: Some(question.stack_id.to_string()),
+                        reason: rollback_reason(&err),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(ScrapeSummary {
+        run_id,
+        url: source.location(),
+        questions_seen: result.questions.len(),
+        questions_new: stored.len(),
+        questions_updated,
+        tags_added,
+        tags_removed,
+        tags_created,
+        details_stored,
+        skipped,
+        rolled_back,
+    })
+}
+
+// Scheduled scrape run, the outcome is only logged
+pub async fn scrape_job(pool: Pool, log: Logger, config: &ScraperConfig, fetcher: &Fetcher) {
+    match scrape_questions(pool, log.clone(), config, fetcher).await {
+        Ok(Some(summary)) => {
+            info!(
+                log,
+                "Scraped {} (run {}): {} seen, {} new, {} updated, {} skipped, {} rolled back",
+                summary.url,
+                summary.run_id,
+                summary.questions_seen,
+                summary.questions_new,
+                summary.questions_updated,
+                summary.skipped.len(),
+                summary.rolled_back.len()
+            );
+            for skipped in summary.skipped.iter().chain(&summary.rolled_back) {
+                warn!(log, "Skipped post {:?}: {}", skipped.post_id, skipped.reason);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => error!(log, "Scrape run failed: {:?}", err),
+    }
+}
+
+// Page of a scraped question on its site. It is built from the site and the stack id, never from
+// the stored link, which hand-written questions can point anywhere
+pub fn question_detail_url(question: &QuestionWithTags) -> Result<String, AppError> {
+    let stack_id = question.question.stack_id.ok_or(AppError {
+        cause: Some(format!("Question {} was not scraped, it has no page to fetch", question.question.question_id)),
+        message: None,
+        error_type: AppErrorType::ValidationError,
+    })?;
+    Ok(site_url(&question.site)
+        .join(&format!("questions/{}", stack_id))
+        .unwrap()
+        .to_string())
+}
+
+// Job of the question_detail kind, fetches the full body and the answers of a scraped question
+pub async fn question_detail_job(
+    pool: Pool,
+    log: Logger,
+    fetcher: &Fetcher,
+    job: QuestionDetailJob,
+) -> Result<(), AppError> {
+    let mut client: Client = pool.get().await?;
+    let question = db::get_question(&**client, job.question_id).await?;
+    let url = question_detail_url(&question)?;
+    let detail = fetch_question_detail(&log, fetcher, &url).await?;
+    let transaction = client.transaction().await?;
+    db::save_question_detail(&*transaction, job.question_id, &detail).await?;
+    transaction.commit().await?;
     Ok(())
 }
 
@@ -135,6 +389,68 @@ pub async fn get_questions(state: web::Data<AppState>) -> Result<impl Responder,
     })
 }
 
+pub async fn get_question(
+    state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_question"));
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_question(&**client, path.0).await;
+
+    result.map(|question| {
+        let ctx = SingleQuestionTemplate { question }.render_once().unwrap();
+        HttpResponse::Ok().body(ctx)
+    })
+}
+
+// An empty search only renders the search box
+pub async fn search_questions(
+    state: web::Data<AppState>,
+    query: web::Query<SearchQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "search_questions"));
+    let (results, total) = if query.q.trim().is_empty() {
+        (Vec::new(), 0)
+    } else {
+        query.validate().map_err(|err| {
+            crit!(sublog, "{:?}", err);
+            AppError::from(err)
+        })?;
+        let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+        db::search_questions(&client, &query).await?
+    };
+
+    let ctx = SearchTemplate {
+        q: query.q.clone(),
+        tag: query.tag.clone().unwrap_or_default(),
+        total,
+        results,
+    }
+    .render_once()
+    .unwrap();
+    Ok(HttpResponse::Ok().body(ctx))
+}
+
+pub async fn get_scrape_runs(
+    state: web::Data<AppState>,
+    query: web::Query<ScrapeRunQuery>,
+) -> Result<impl Responder, AppError> {
+    let sublog = state.log.new(o!("handler" => "get_scrape_runs"));
+    query.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
+    let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
+
+    let result = db::get_scrape_runs(&client, query.limit).await;
+
+    result.map(|runs| {
+        let ctx = ScrapeRunsTemplate { runs }.render_once().unwrap();
+        HttpResponse::Ok().body(ctx)
+    })
+}
+
 // we can use the actix web extracter to get the param
 pub async fn get_questions_by_tag(
     state: web::Data<AppState>,
@@ -167,24 +483,62 @@ pub async fn create_tag(
     let result = db::create_tag(&client, form.tag_title.clone()).await;
 
     result.map(|tag| {
-        let ctx = CreateTagTemplate { tag: tag }.render_once().unwrap();
+        let ctx = CreateTagTemplate { tag }.render_once().unwrap();
         HttpResponse::Ok().body(ctx)
     })
 }
 
 pub async fn update_tag(
     state: web::Data<AppState>,
-    json: web::Json<Tag>,
+    json: web::Json<UpdateTag>,
 ) -> Result<impl Responder, AppError> {
     let sublog = state.log.new(o!("handler" => "update_tag"));
+    json.validate().map_err(|err| {
+        crit!(sublog, "{:?}", err);
+        AppError::from(err)
+    })?;
     let client: Client = configure_pool(state.pool.clone(), sublog.clone()).await?;
 
-    let result = db::update_tag(&client, json.tag_id.clone(), json.tag_title.clone()).await;
+    let result = db::update_tag(&client, json.tag_id, json.tag_title.clone()).await;
 
-    result.map(|updated| {
+    result.map(|_| {
         HttpResponse::Ok().json(ResultResponse {
             message: "operation completed sucessfully".to_string(),
-            success: updated,
+            success: true,
         })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::question_detail_url;
+    use crate::models::{QuestionWithTags, Questions};
+
+    fn question(site: &str, stack_id: Option<i32>, question_link: &str) -> QuestionWithTags {
+        QuestionWithTags {
+            question: Questions {
+                question_id: 1,
+                title: "title".to_string(),
+                q_description: "description".to_string(),
+                question_link: Some(question_link.to_string()),
+                votes: 0,
+                views: 0,
+                stack_id,
+                answer: 0,
+            },
+            site: site.to_string(),
+            body_html: None,
+            tags: Vec::new(),
+            answers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_question_detail_url_ignores_the_stored_link() {
+        let scraped = question("serverfault", Some(123), "http://169.254.169.254/latest/meta-data");
+        assert_eq!(question_detail_url(&scraped).unwrap(), "https://serverfault.com/questions/123");
+
+        let hand_written = question("stackoverflow", None, "http://169.254.169.254/latest/meta-data");
+        assert!(question_detail_url(&hand_written).is_err());
+    }
+}
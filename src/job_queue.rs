@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_postgres::{Client, Pool};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use slog::{error, info, o, warn, Logger};
+use tokio::sync::{watch, Notify};
+
+use crate::config::JobQueueConfig;
+use crate::db;
+use crate::error::{AppError, AppErrorType};
+use crate::models::Job;
+use crate::scheduler::Scheduler;
+
+// Fetches the detail page of a question, the payload is a QuestionDetailJob
+pub const QUESTION_DETAIL_JOB: &str = "question_detail";
+
+type JobHandler = Rc<dyn Fn(serde_json::Value) -> Pin<Box<dyn Future<Output = Result<(), AppError>>>>>;
+
+// Enqueues jobs from the handlers. Jobs are stored in the jobs table, so they outlive restarts and
+// are shared by every instance using the database
+pub struct JobQueue {
+    enqueued: Notify,
+    max_attempts: i32,
+}
+
+// Runs the jobs of the registered kinds. A failed job is retried with an exponential backoff until
+// its attempts are used up, then it is dead. A job failing for good, like one with a bad payload,
+// is dead right away. A job which is not finished within the visibility
+// timeout is given up and taken by the next free worker, also on another instance
+pub struct JobWorkers {
+    pool: Pool,
+    log: Logger,
+    config: JobQueueConfig,
+    instance: String,
+    handlers: HashMap<String, JobHandler>,
+}
+
+impl JobQueue {
+    pub fn new(config: &JobQueueConfig) -> Self {
+        JobQueue {
+            enqueued: Notify::new(),
+            max_attempts: config.max_attempts,
+        }
+    }
+
+    pub async fn enqueue<P: Serialize>(&self, client: &Client, kind: &str, payload: &P) -> Result<Job, AppError> {
+        let payload = serde_json::to_value(payload).unwrap();
+        let job = db::enqueue_job(client, kind, &payload, self.max_attempts).await?;
+        self.enqueued.notify_one();
+        Ok(job)
+    }
+}
+
+impl JobWorkers {
+    pub fn new(pool: Pool, log: Logger, config: JobQueueConfig, instance: &str) -> Self {
+        JobWorkers {
+            pool,
+            log,
+            config,
+            instance: instance.to_string(),
+            handlers: HashMap::new(),
+        }
+    }
+
+    // Runs the jobs of `kind`, their payload is handed over as `P`. A payload which doesn't
+    // deserialize fails the job
+    pub fn register<P, F, Fut>(&mut self, kind: &str, handler: F)
+    where
+        P: DeserializeOwned + 'static,
+        F: Fn(P) -> Fut + 'static,
+        Fut: Future<Output = Result<(), AppError>> + 'static,
+    {
+        let handler = Rc::new(handler);
+        self.handlers.insert(
+            kind.to_string(),
+            Rc::new(move |payload| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let payload = serde_json::from_value(payload).map_err(|err| AppError {
+                        cause: Some(format!("bad payload: {}", err)),
+                        message: None,
+                        error_type: AppErrorType::ValidationError,
+                    })?;
+                    handler(payload).await
+                })
+            }),
+        );
+    }
+
+    // Every worker runs as a worker of the scheduler, so stopping the scheduler lets the running
+    // jobs finish
+    pub fn start(self, queue: Arc<JobQueue>, scheduler: &mut Scheduler) {
+        let workers = Rc::new(self);
+        for index in 0..workers.config.workers {
+            let (workers, queue) = (workers.clone(), queue.clone());
+            let name = format!("jobs-{}", index);
+            let worker = format!("{}/{}", workers.instance, name);
+            scheduler.add_worker(&name, move |stopped| async move {
+                workers.work(&worker, &queue, stopped).await
+            });
+        }
+    }
+
+    async fn work(&self, worker: &str, queue: &JobQueue, mut stopped: watch::Receiver<bool>) {
+        let log = self.log.new(o!("worker" => worker.to_string()));
+        let kinds: Vec<&str> = self.handlers.keys().map(String::as_str).collect();
+        let visibility_timeout = Duration::from_secs(self.config.visibility_timeout_secs);
+        while !*stopped.borrow() {
+            match self.claim(&kinds, worker, visibility_timeout).await {
+                Ok(Some(job)) => {
+                    self.run(&log, worker, job, visibility_timeout).await;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err) => error!(log, "Claiming a job failed: {:?}", err),
+            }
+            tokio::select! {
+                _ = queue.enqueued.notified() => {}
+                _ = actix_rt::time::sleep(Duration::from_secs(self.config.poll_secs)) => {}
+                _ = stopped.changed() => {}
+            }
+        }
+    }
+
+    async fn claim(&self, kinds: &[&str], worker: &str, visibility_timeout: Duration) -> Result<Option<Job>, AppError> {
+        let client = self.pool.get().await?;
+        db::bury_timed_out_jobs(&client).await?;
+        db::claim_job(&client, kinds, worker, visibility_timeout.as_secs_f64()).await
+    }
+
+    async fn run(&self, log: &Logger, worker: &str, job: Job, visibility_timeout: Duration) {
+        let log = log.new(o!("job_id" => job.job_id, "kind" => job.kind.clone()));
+        info!(log, "Running job {} ({}), attempt {} of {}", job.job_id, job.kind, job.attempts, job.max_attempts);
+        let handler = &self.handlers[&job.kind];
+        // the job is given up with the lock, another worker may have taken it by then
+        let result = match actix_rt::time::timeout(visibility_timeout, handler(job.payload)).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError {
+                cause: Some(format!("timed out after {} seconds", visibility_timeout.as_secs())),
+                message: None,
+                error_type: AppErrorType::TimeoutError,
+            }),
+        };
+
+        let recorded = match (self.pool.get().await, result) {
+            (Ok(client), Ok(())) => db::complete_job(&client, job.job_id, worker).await,
+            (Ok(client), Err(err)) => {
+                let permanent = is_permanent(&err);
+                let cause = err.cause.or(err.message).unwrap_or_else(|| "unknown error".to_string());
+                let retry_in = (!permanent && job.attempts < job.max_attempts).then(|| backoff(&self.config, job.attempts));
+                match retry_in {
+                    Some(retry_in) => warn!(log, "Job {} failed, retrying in {:?}: {}", job.job_id, retry_in, cause),
+                    None if permanent => warn!(log, "Job {} can't succeed, it is dead: {}", job.job_id, cause),
+                    None => warn!(log, "Job {} failed for the last time, it is dead: {}", job.job_id, cause),
+                }
+                let retry_in = retry_in.map(|retry_in| retry_in.as_secs_f64());
+                db::fail_job(&client, job.job_id, worker, &cause, retry_in).await
+            }
+            (Err(err), _) => Err(AppError::from(err)),
+        };
+        if let Err(err) = recorded {
+            error!(log, "Recording the outcome of job {} failed: {:?}", job.job_id, err);
+        }
+    }
+}
+
+// Invalid jobs, like a bad payload or a question without a link, jobs about rows which are gone and
+// pages robots.txt disallows fail the same way on every attempt
+fn is_permanent(err: &AppError) -> bool {
+    matches!(
+        err.error_type,
+        AppErrorType::ValidationError | AppErrorType::NotFoundError | AppErrorType::DisallowedError
+    )
+}
+
+// Delay before the retry following the given attempt, doubled with every attempt
+pub fn backoff(config: &JobQueueConfig, attempt: i32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt.max(1) as u32 - 1);
+    Duration::from_secs(
+        config
+            .backoff_base_secs
+            .saturating_mul(factor)
+            .min(config.backoff_max_secs),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{backoff, is_permanent};
+    use crate::config::JobQueueConfig;
+    use crate::error::{AppError, AppErrorType, ScrapeError};
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        let config = JobQueueConfig {
+            backoff_base_secs: 10,
+            backoff_max_secs: 60,
+            ..JobQueueConfig::default()
+        };
+        let delays: Vec<Duration> = (1..=5).map(|attempt| backoff(&config, attempt)).collect();
+        let secs: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+
+        assert_eq!(secs, vec![10, 20, 40, 60, 60]);
+        assert_eq!(backoff(&config, 100).as_secs(), 60);
+    }
+
+    #[test]
+    fn test_only_transient_failures_are_retried() {
+        let error = |error_type| AppError {
+            cause: None,
+            message: None,
+            error_type,
+        };
+
+        assert!(is_permanent(&error(AppErrorType::ValidationError)));
+        assert!(is_permanent(&error(AppErrorType::NotFoundError)));
+        assert!(is_permanent(&AppError::from(ScrapeError::RobotsDisallowed("/private".to_string()))));
+        assert!(!is_permanent(&AppError::from(ScrapeError::Network("timed out".to_string()))));
+        assert!(!is_permanent(&error(AppErrorType::DbError)));
+        assert!(!is_permanent(&error(AppErrorType::ScrapeError)));
+        assert!(!is_permanent(&error(AppErrorType::TimeoutError)));
+    }
+}
@@ -7,41 +7,41 @@ mod api_handlers;
 mod config;
 mod db;
 mod error;
+mod fetcher;
 mod handlers;
+mod job_queue;
+mod migrations;
 mod models;
+mod scheduler;
+mod scrape_queue;
 mod scraper;
-// mod scheduler;
+mod source;
+mod stackexchange;
 
-use std::str::FromStr;
-use std::time::Duration;
+use std::rc::Rc;
 
 use crate::api_handlers as api;
+use crate::fetcher::Fetcher;
 use crate::handlers::*;
+use crate::job_queue::{JobQueue, JobWorkers, QUESTION_DETAIL_JOB};
 use crate::models::AppState;
-// use crate::scheduler::Scheduler;
-// use actix::Actor;
+use crate::scheduler::Scheduler;
+use crate::scrape_queue::{ScrapeQueue, ScrapeWorker};
 use actix_files as fs;
-use actix_rt::time;
 use actix_web::HttpResponse;
 use actix_web::Responder;
 use actix_web::{web, App, HttpServer};
 
-use chrono::FixedOffset;
-use chrono::Local;
-use cron::Schedule;
 use deadpool_postgres::Runtime;
 use dotenv::dotenv;
 use tokio_postgres::NoTls;
 
 // IT is used as a logging middleware. We can even use the default logger with actix. keyword fuse is used to painck
-use slog;
 use slog::{info, o, Drain, Logger};
-use slog_async;
-use slog_term;
 
 mod broadcast;
 use self::broadcast::Broadcaster;
-use std::{io, sync::Arc};
+use std::sync::Arc;
 use actix_web_lab::extract::Path;
 
 fn configure_log() -> Logger {
@@ -74,88 +74,177 @@ async fn main() -> std::io::Result<()> {
 
     let log = configure_log();
 
+    // the schema is brought up to date before anything touches the database
+    let mut client = pool.get().await.unwrap();
+    let applied = migrations::run(&mut client, &log).await.unwrap();
+    info!(log, "Database schema is up to date, applied {} migrations", applied.len());
+    drop(client);
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        return Ok(());
+    }
+
     info!(
         log,
         "Starting the server at http://{}:{}/", config.server.host, config.server.port
     );
-    // let scheduler_obj = Scheduler {pool:pool.clone(),log:log.clone()};
-    // Scheduler::start(scheduler_obj);
-
-    // actix_rt::spawn(async move {
-    //     let mut interval = time::interval(Duration::from_secs(120));
-    //     let new_pool = config.pg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
-
-    //     let new_log = configure_log();
-    //     loop {
-    //         interval.tick().await;
-    //         println!("120 seconds");
-    //         scrape_questions(new_pool.clone(), new_log.clone()).await.unwrap();
-    //     }
-    // });
-
-    actix_rt::spawn(async move {
-        let expression = "1/50   *   *     *       *  *  *";
-        let schedule = Schedule::from_str(expression).unwrap();
-        let offset = Some(FixedOffset::east(0)).unwrap();
-        let new_pool = config.pg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
-        let new_log = configure_log();
-
-        loop {
-            let mut upcoming = schedule.upcoming(offset).take(1);
-            actix_rt::time::sleep(Duration::from_millis(500)).await;
-            let local = &Local::now();
-
-            if let Some(datetime) = upcoming.next() {
-                if datetime.timestamp() <= local.timestamp() {
-                    println!("120 seconds");
-                    scrape_questions(new_pool.clone(), new_log.clone())
-                        .await
-                        .unwrap();
-                }
-            }
-        }
+    let scraper_config = config.scraper.clone();
+    let fetcher = Rc::new(Fetcher::new(&scraper_config).unwrap());
+    let mut scheduler = Scheduler::new(log.clone());
+    scheduler.coordinate(pool.clone(), &config.scheduler.instance);
+    let (job_pool, job_log) = (pool.clone(), log.clone());
+    let (job_config, job_fetcher) = (scraper_config.clone(), fetcher.clone());
+    scheduler
+        .add("scrape", &config.scheduler.scrape, move || {
+            let (pool, log) = (job_pool.clone(), job_log.clone());
+            let (scraper_config, fetcher) = (job_config.clone(), job_fetcher.clone());
+            async move { scrape_job(pool, log, &scraper_config, &fetcher).await }
+        })
+        .unwrap();
+    // on-demand scrapes posted to /api/scrape
+    let scrape_queue = Arc::new(ScrapeQueue::default());
+    let worker = ScrapeWorker {
+        pool: pool.clone(),
+        log: log.new(o!("worker" => "scrape-queue")),
+        worker: format!("{}/scrape-queue", config.scheduler.instance),
+        config: scraper_config,
+        fetcher: fetcher.clone(),
+        broadcaster: Arc::clone(&broadcaster),
+    };
+    let queue = Arc::clone(&scrape_queue);
+    scheduler.add_worker("scrape-queue", move |stopped| queue.work(worker, stopped));
+    // background jobs enqueued by the handlers
+    let jobs = Arc::new(JobQueue::new(&config.jobs));
+    let mut workers = JobWorkers::new(pool.clone(), log.clone(), config.jobs.clone(), &config.scheduler.instance);
+    let (job_pool, job_log, job_fetcher) = (pool.clone(), log.clone(), fetcher);
+    workers.register(QUESTION_DETAIL_JOB, move |job| {
+        let (pool, log, fetcher) = (job_pool.clone(), job_log.clone(), job_fetcher.clone());
+        async move { question_detail_job(pool, log, &fetcher, job).await }
     });
+    workers.start(Arc::clone(&jobs), &mut scheduler);
+    let scheduler = scheduler.start();
 
     info!(log, "Testing");
 
     // we need to pass the ownership so we use the move
     // AS the web server make instance for each thread to we need to pass the pool
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(AppState {
                 pool: pool.clone(),
                 log: log.clone(),
-                broadcaster:Arc::clone(&broadcaster)
+                broadcaster:Arc::clone(&broadcaster),
+                scrape_queue: Arc::clone(&scrape_queue),
+                jobs: Arc::clone(&jobs),
             }))
             .service(fs::Files::new("/static", "./static").show_files_listing())
             .route("/", web::get().to(home_page))
-            // .route("/scrape{_:/?}", web::get().to(scrape_questions))
             .route("/tags{_:/?}", web::get().to(get_tags))
             .route("/tags{_:/?}", web::post().to(create_tag))
             .route("/tags/update/{tag_id}{_:/?}", web::post().to(update_tag))
             .route("/questions{_:/?}", web::get().to(get_questions))
             .route(
-                "/questions/{tag_id}{_:/?}",
+                "/tags/{tag_id}/questions{_:/?}",
                 web::get().to(get_questions_by_tag),
             )
+            .route("/questions/{question_id}{_:/?}", web::get().to(get_question))
+            .route("/search{_:/?}", web::get().to(search_questions))
+            .route("/admin/scrape-runs{_:/?}", web::get().to(get_scrape_runs))
             .route("/events{_:/?}", web::get().to(sse_client))
             .route("/events/{msg}", web::get().to(broadcast_msg))
             .route("/api/tags{_:/?}", web::put().to(api::update_tag))
             .route("/api/tags{_:/?}", web::get().to(api::get_tags))
             .route("/api/tags{_:/?}", web::post().to(api::create_tag))
+            .route("/api/tags/{tag_id}{_:/?}", web::delete().to(api::delete_tag))
+            .route(
+                "/api/tags/{tag_id}/merge-into/{into_tag_id}{_:/?}",
+                web::post().to(api::merge_tag),
+            )
             .route("/api/questions{_:/?}", web::get().to(api::get_questions))
+            .route("/api/questions{_:/?}", web::post().to(api::create_question))
+            .route("/api/search{_:/?}", web::get().to(api::search_questions))
+            // registered before the single question so `rising` is not taken for a question id
+            .route(
+                "/api/questions/rising{_:/?}",
+                web::get().to(api::get_rising_questions),
+            )
+            .route(
+                "/api/questions/{question_id}/history{_:/?}",
+                web::get().to(api::get_question_history),
+            )
+            .route(
+                "/api/questions/{question_id}/detail{_:/?}",
+                web::post().to(api::refresh_question_detail),
+            )
+            .route(
+                "/api/questions/{question_id}{_:/?}",
+                web::get().to(api::get_question),
+            )
+            .route(
+                "/api/questions/{question_id}{_:/?}",
+                web::put().to(api::replace_question),
+            )
+            .route(
+                "/api/questions/{question_id}{_:/?}",
+                web::patch().to(api::patch_question),
+            )
+            .route(
+                "/api/questions/{question_id}{_:/?}",
+                web::delete().to(api::delete_question),
+            )
             .route(
-                "/api/questions/{tag_id}{_:/?}",
+                "/api/tags/{tag_id}/questions{_:/?}",
                 web::get().to(api::get_questions_by_tag),
             )
+            .route(
+                "/api/tag-synonyms{_:/?}",
+                web::get().to(api::get_tag_synonyms),
+            )
+            .route(
+                "/api/tag-synonyms{_:/?}",
+                web::post().to(api::create_tag_synonym),
+            )
+            .route(
+                "/api/tag-synonyms/collapse{_:/?}",
+                web::post().to(api::collapse_tags),
+            )
+            .route(
+                "/api/tag-synonyms/{synonym_id}{_:/?}",
+                web::delete().to(api::delete_tag_synonym),
+            )
+            .route(
+                "/api/scrape-targets{_:/?}",
+                web::get().to(api::get_scrape_targets),
+            )
+            .route(
+                "/api/scrape-targets{_:/?}",
+                web::post().to(api::create_scrape_target),
+            )
+            .route(
+                "/api/scrape-targets{_:/?}",
+                web::put().to(api::update_scrape_target),
+            )
+            .route(
+                "/api/scrape-targets/{target_id}{_:/?}",
+                web::delete().to(api::delete_scrape_target),
+            )
+            .route("/api/scrape-runs{_:/?}", web::get().to(api::get_scrape_runs))
+            .route("/api/scrape{_:/?}", web::post().to(api::enqueue_scrape))
+            .route("/api/jobs{_:/?}", web::get().to(api::get_jobs))
+            .route("/api/jobs/{job_id}{_:/?}", web::get().to(api::get_job))
+            .route("/api/jobs/{job_id}/retry{_:/?}", web::post().to(api::retry_job))
+            .route("/api/scrape/{job_id}{_:/?}", web::get().to(api::get_scrape_job))
     })
     .bind(format!("{}:{}", config.server.host, config.server.port))?
     .run()
-    .await
+    .await;
+
+    // the server has stopped, a running scrape is finished before exiting
+    scheduler.stop().await;
+    server
 }
 
 // sudo service postgresql stop
 // sudo update-rc.d postgresql disable
 // sudo docker-compose up -d
-// sudo psql -h 127.0.0.1 -p 5432 -U actix actix < database.sql
+// cargo run -- --migrate-only
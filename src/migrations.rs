@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use deadpool_postgres::Client;
+use sha2::{Digest, Sha256};
+use slog::{info, Logger};
+
+use crate::error::{AppError, AppErrorType};
+
+// Schema changes embedded in the binary, applied in order of their version. An applied migration
+// must never be edited, its checksum is compared on every start. Add a new file instead
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: [Migration; 15] = [
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "scrape_targets",
+        sql: include_str!("../migrations/0002_scrape_targets.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "views_to_integer",
+        sql: include_str!("../migrations/0003_views_to_integer.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "question_details",
+        sql: include_str!("../migrations/0004_question_details.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "scrape_target_sources",
+        sql: include_str!("../migrations/0005_scrape_target_sources.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "question_snapshots",
+        sql: include_str!("../migrations/0006_question_snapshots.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "question_search",
+        sql: include_str!("../migrations/0007_question_search.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "manual_questions",
+        sql: include_str!("../migrations/0008_manual_questions.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "tag_synonyms",
+        sql: include_str!("../migrations/0009_tag_synonyms.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "scrape_runs",
+        sql: include_str!("../migrations/0010_scrape_runs.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "scrape_jobs",
+        sql: include_str!("../migrations/0011_scrape_jobs.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "scheduler_leases",
+        sql: include_str!("../migrations/0012_scheduler_leases.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "jobs",
+        sql: include_str!("../migrations/0013_jobs.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "question_sites",
+        sql: include_str!("../migrations/0014_question_sites.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "scrape_job_leases",
+        sql: include_str!("../migrations/0015_scrape_job_leases.sql"),
+    },
+];
+
+// Any session key works as long as every instance uses the same one
+const MIGRATION_LOCK: i64 = 7_305_112_014;
+
+pub fn checksum(sql: &str) -> String {
+    format!("{:x}", Sha256::digest(sql.as_bytes()))
+}
+
+fn migration_error(message: String) -> AppError {
+    AppError {
+        cause: None,
+        message: Some(message),
+        error_type: AppErrorType::DbError,
+    }
+}
+
+// Applies the pending migrations, each in its own transaction, and returns their versions.
+// Instances starting together wait on an advisory lock so a migration is never applied twice
+pub async fn run(client: &mut Client, log: &Logger) -> Result<Vec<i32>, AppError> {
+    client
+        .batch_execute(
+            "create table if not exists schema_migrations (
+                version integer primary key,
+                name varchar(100) not null,
+                checksum varchar(64) not null,
+                applied_at timestamptz not null default now()
+            );",
+        )
+        .await?;
+    client
+        .execute("select pg_advisory_lock($1);", &[&MIGRATION_LOCK])
+        .await?;
+    let result = apply_pending(client, log).await;
+    client
+        .execute("select pg_advisory_unlock($1);", &[&MIGRATION_LOCK])
+        .await?;
+    result
+}
+
+async fn apply_pending(client: &mut Client, log: &Logger) -> Result<Vec<i32>, AppError> {
+    let applied: HashMap<i32, String> = client
+        .query("select version, checksum from schema_migrations;", &[])
+        .await?
+        .iter()
+        .map(|row| (row.get("version"), row.get("checksum")))
+        .collect();
+
+    if let Some(version) = applied
+        .keys()
+        .find(|version| !MIGRATIONS.iter().any(|m| m.version == **version))
+    {
+        return Err(migration_error(format!(
+            "Migration {} is applied to the database but unknown to this build",
+            version
+        )));
+    }
+
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS.iter() {
+        let checksum = checksum(migration.sql);
+        match applied.get(&migration.version) {
+            Some(applied_checksum) if *applied_checksum == checksum => continue,
+            Some(_) => {
+                return Err(migration_error(format!(
+                    "Migration {} ({}) was changed after it was applied",
+                    migration.version, migration.name
+                )))
+            }
+            None => {}
+        }
+
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(migration.sql).await?;
+        transaction
+            .execute(
+                "insert into schema_migrations (version,name,checksum) values ($1,$2,$3);",
+                &[&migration.version, &migration.name, &checksum],
+            )
+            .await?;
+        transaction.commit().await?;
+        info!(log, "Applied migration {} ({})", migration.version, migration.name);
+        newly_applied.push(migration.version);
+    }
+    Ok(newly_applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, MIGRATIONS};
+
+    #[test]
+    fn test_versions_are_consecutive() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i32 + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn test_migrations_leave_transactions_to_the_runner() {
+        for migration in MIGRATIONS.iter() {
+            let lines: Vec<&str> = migration.sql.lines().map(str::trim).collect();
+            assert!(!lines.contains(&"begin;"), "{}", migration.name);
+            assert!(!lines.contains(&"commit;"), "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum("select 1;"), checksum("select 1;"));
+        assert_ne!(checksum("select 1;"), checksum("select 2;"));
+        assert_eq!(checksum("").len(), 64);
+    }
+}
@@ -1,12 +1,15 @@
-use std::{collections::{HashSet, HashMap}, sync::Arc};
+use std::{collections::{HashSet, HashMap}, path::{Component, Path}, sync::Arc};
 
+use chrono::{DateTime, Utc};
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use slog::Logger;
 use tokio_pg_mapper_derive::PostgresMapper;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 use crate::broadcast::Broadcaster;
+use crate::job_queue::JobQueue;
+use crate::scrape_queue::ScrapeQueue;
 
 #[derive(Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "question")]
@@ -14,10 +17,11 @@ pub struct Questions {
     pub question_id: i32,
     pub title: String,
     pub q_description: String,
-    pub question_link: String,
+    // hand-written questions have neither a link nor a stack id
+    pub question_link: Option<String>,
     pub votes: i32,
-    pub views: String,
-    pub stack_id: i32,
+    pub views: i32,
+    pub stack_id: Option<i32>,
     pub answer: i32,
 }
 
@@ -31,13 +35,60 @@ pub struct Tag {
 #[pg_mapper(table = "tag")]
 pub struct TagId {
     pub tag_id: i32,
+    // false when an existing tag or synonym matched
+    pub created: bool,
 }
 
-#[derive(Serialize, Deserialize, PostgresMapper)]
-#[pg_mapper(table = "tag_question")]
-pub struct TagQuestion {
+#[derive(Serialize)]
+pub struct TagMerge {
+    // the tag the questions were moved to
+    pub tag: Tag,
+    pub questions_moved: u64,
+}
+
+// Scraped tags named like the synonym get its tag instead, the case is ignored
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "tag_synonym")]
+pub struct TagSynonym {
+    pub synonym_id: i32,
+    pub synonym: String,
     pub tag_id: i32,
-    pub question_id: i32,
+    pub tag_title: String,
+}
+
+#[derive(Validate, Deserialize)]
+pub struct CreateTagSynonym {
+    #[validate(length(min = 1, max = 30))]
+    pub synonym: String,
+    pub tag_id: i32,
+}
+
+// A tag named like a synonym which was merged into the tag of the synonym
+#[derive(Serialize, Debug)]
+pub struct CollapsedTag {
+    pub tag_title: String,
+    pub into_tag_id: i32,
+    pub questions_moved: u64,
+}
+
+#[derive(Serialize)]
+pub struct SynonymCreated {
+    pub synonym: TagSynonym,
+    pub collapsed: Vec<CollapsedTag>,
+}
+
+// A single question with everything stored about it
+#[derive(Serialize)]
+pub struct QuestionWithTags {
+    #[serde(flatten)]
+    pub question: Questions,
+    // Stack Exchange site the stack id belongs to
+    pub site: String,
+    // only stored when SCRAPER.FETCH_DETAILS is on
+    pub body_html: Option<String>,
+    pub tags: Vec<Tag>,
+    // accepted answer first, then by score
+    pub answers: Vec<ScrapedAnswer>,
 }
 
 #[derive(Serialize, Deserialize, PostgresMapper)]
@@ -47,11 +98,11 @@ pub struct TagQuestionRelation {
     pub question_id: i32,
     pub q_title: String,
     pub q_description: String,
-    pub question_link: String,
+    pub question_link: Option<String>,
     pub votes: i32,
-    pub views: String,
+    pub views: i32,
     pub tag_title: String,
-    pub stack_id: i32,
+    pub stack_id: Option<i32>,
     pub answer: i32,
 }
 
@@ -61,15 +112,81 @@ pub struct CreateTag {
     pub tag_title: String,
 }
 
+#[derive(Validate, Deserialize)]
+pub struct UpdateTag {
+    pub tag_id: i32,
+    #[validate(length(min = 1))]
+    pub tag_title: String,
+}
+
+// A tag given by its id, or by its title which is created when missing
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum TagRef {
+    Id(i32),
+    Title(String),
+}
+
+fn validate_tag_refs(tags: &[TagRef]) -> Result<(), ValidationError> {
+    let valid = tags.iter().all(|tag| match tag {
+        TagRef::Id(tag_id) => *tag_id > 0,
+        TagRef::Title(title) => !title.trim().is_empty() && title.len() <= 30,
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("tags are ids or titles of 1 to 30 characters"))
+    }
+}
+
+// Links are rendered as hrefs, so only web pages are accepted and never javascript: or data: urls
+fn validate_question_link(link: &str) -> Result<(), ValidationError> {
+    match reqwest::Url::parse(link) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(()),
+        _ => Err(ValidationError::new("question_link must be an http or https url")),
+    }
+}
+
+// Hand-written questions, also used to replace a question as a whole
+#[derive(Validate, Serialize, Deserialize, Debug)]
 pub struct CreateQuestion {
+    #[validate(length(min = 1, max = 200))]
     pub title: String,
+    #[validate(length(min = 1, max = 1000))]
     pub q_description: String,
-    pub question_link: String,
+    #[validate(length(max = 200), custom = "validate_question_link")]
+    pub question_link: Option<String>,
+    #[serde(default)]
     pub votes: i32,
-    pub views: String,
+    #[serde(default)]
+    #[validate(range(min = 0))]
+    pub views: i32,
+    #[serde(default)]
+    #[validate(range(min = 0))]
     pub answer: i32,
-    pub stack_id: i32,
+    #[validate(range(min = 1))]
+    pub stack_id: Option<i32>,
+    #[serde(default)]
+    #[validate(custom = "validate_tag_refs")]
+    pub tags: Vec<TagRef>,
+}
+
+// Only the given fields are changed, the tags are replaced when given
+#[derive(Validate, Serialize, Deserialize, Debug)]
+pub struct UpdateQuestion {
+    #[validate(length(min = 1, max = 200))]
+    pub title: Option<String>,
+    #[validate(length(min = 1, max = 1000))]
+    pub q_description: Option<String>,
+    #[validate(length(max = 200), custom = "validate_question_link")]
+    pub question_link: Option<String>,
+    pub votes: Option<i32>,
+    #[validate(range(min = 0))]
+    pub views: Option<i32>,
+    #[validate(range(min = 0))]
+    pub answer: Option<i32>,
+    #[validate(custom = "validate_tag_refs")]
+    pub tags: Option<Vec<TagRef>>,
 }
 
 #[derive(Serialize)]
@@ -82,12 +199,56 @@ pub struct AppState {
     pub pool: Pool,
     pub log: Logger,
     pub broadcaster:Arc<Broadcaster>,
+    pub scrape_queue: Arc<ScrapeQueue>,
+    pub jobs: Arc<JobQueue>,
 }
 
 #[derive(Debug,Serialize, Deserialize)]
 pub struct ScraperResult {
     pub questions: Vec<ScrapedQuestion>,
-    pub unique_tags:HashMap<String,i32>
+    pub unique_tags:HashMap<String,i32>,
+    pub skipped: Vec<SkippedPost>,
+}
+
+// A post summary which could not be parsed and was left out of the scrape
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SkippedPost {
+    pub post_id: Option<String>,
+    pub reason: String,
+}
+
+// Outcome of a whole scrape run
+#[derive(Debug, Serialize)]
+pub struct ScrapeSummary {
+    pub run_id: i32,
+    pub url: String,
+    pub questions_seen: usize,
+    pub questions_new: usize,
+    // questions stored before whose statistics were refreshed
+    pub questions_updated: usize,
+    pub tags_added: u64,
+    pub tags_removed: u64,
+    pub tags_created: u64,
+    pub details_stored: usize,
+    // posts left out while fetching or parsing
+    pub skipped: Vec<SkippedPost>,
+    // questions whose writes were rolled back, the rest of the batch is committed
+    pub rolled_back: Vec<SkippedPost>,
+}
+
+// Everything the question page adds on top of the listing summary
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuestionDetail {
+    pub body_html: String,
+    pub answers: Vec<ScrapedAnswer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScrapedAnswer {
+    pub stack_answer_id: i32,
+    pub body_html: String,
+    pub score: i32,
+    pub is_accepted: bool,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ScrapedQuestion {
@@ -96,13 +257,543 @@ pub struct ScrapedQuestion {
     pub question_link: String,
     pub votes: i32,
     pub stack_id: i32,
-    pub views: String,
+    pub views: i32,
     pub tags: HashSet<String>,
     pub answer: i32,
 }
 
 #[derive(Debug,PostgresMapper)]
 #[pg_mapper(table = "question")]
-pub struct QuestionId {
-    pub question_id: i32 
-}
\ No newline at end of file
+pub struct UpsertedQuestion {
+    pub question_id: i32,
+    pub stack_id: i32,
+    pub inserted: bool,
+}
+
+// Outcome of storing a whole batch of scraped questions at once
+#[derive(Debug)]
+pub struct BulkStored {
+    pub questions: Vec<UpsertedQuestion>,
+    pub tags_added: u64,
+    pub tags_removed: u64,
+    pub tags_created: u64,
+}
+pub const SCRAPE_TABS: [&str; 3] = ["Newest", "Active", "Votes"];
+
+// Only the listing tabs stackoverflow exposes for tagged questions are accepted
+fn validate_tab(tab: &str) -> Result<(), ValidationError> {
+    if SCRAPE_TABS.contains(&tab) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("tab must be one of Newest, Active or Votes"))
+    }
+}
+
+pub const SCRAPE_SOURCES: [&str; 3] = ["html", "api", "dump"];
+
+fn validate_source(source: &str) -> Result<(), ValidationError> {
+    if SCRAPE_SOURCES.contains(&source) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("source must be one of html, api or dump"))
+    }
+}
+
+// Stack Exchange site names like stackoverflow, serverfault or unix
+fn validate_site(site: &str) -> Result<(), ValidationError> {
+    let is_word = |word: &str| {
+        !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    };
+    if site.split('-').all(is_word) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("site must be a stack exchange site name like serverfault"))
+    }
+}
+
+// A dump is read from a file, so a dump target without a path can never be scraped. The path is
+// relative to SCRAPER.DUMP_DIR and may not leave it
+fn validate_dump_path(source: &str, dump_path: &Option<String>) -> Result<(), ValidationError> {
+    let inside_dump_dir = |path: &str| {
+        Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    };
+    match (source, dump_path) {
+        ("dump", None) => Err(ValidationError::new("dump targets need a dump_path")),
+        (_, Some(path)) if !inside_dump_dir(path) => Err(ValidationError::new(
+            "dump_path must be relative to the dump directory, without ..",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_scrape_target(target: &ScrapeTarget) -> Result<(), ValidationError> {
+    validate_dump_path(&target.source, &target.dump_path)
+}
+
+fn validate_create_scrape_target(target: &CreateScrapeTarget) -> Result<(), ValidationError> {
+    validate_dump_path(&target.source, &target.dump_path)
+}
+
+// Anyone reaching the api could read the files of the server with a dump job, dumps are only
+// imported from the scrape targets
+fn validate_create_scrape_job(job: &CreateScrapeJob) -> Result<(), ValidationError> {
+    match job.source.as_str() {
+        "dump" => Err(ValidationError::new("dumps are only imported from scrape targets")),
+        _ => Ok(()),
+    }
+}
+
+fn default_source() -> String {
+    SCRAPE_SOURCES[0].to_string()
+}
+
+fn default_site() -> String {
+    "stackoverflow".to_string()
+}
+
+fn default_tab() -> String {
+    SCRAPE_TABS[0].to_string()
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, PostgresMapper, Validate, Debug)]
+#[pg_mapper(table = "scrape_target")]
+#[validate(schema(function = "validate_scrape_target"))]
+pub struct ScrapeTarget {
+    pub target_id: i32,
+    #[validate(length(min = 1, max = 30))]
+    pub tag_title: String,
+    #[validate(custom = "validate_tab")]
+    pub tab: String,
+    #[validate(range(min = 1))]
+    pub weight: i32,
+    pub enabled: bool,
+    #[validate(custom = "validate_source")]
+    pub source: String,
+    #[validate(length(min = 1, max = 100), custom = "validate_site")]
+    pub site: String,
+    #[validate(length(min = 1, max = 255))]
+    pub dump_path: Option<String>,
+}
+
+#[derive(Validate, Deserialize)]
+#[validate(schema(function = "validate_create_scrape_target"))]
+pub struct CreateScrapeTarget {
+    #[validate(length(min = 1, max = 30))]
+    pub tag_title: String,
+    #[serde(default = "default_tab")]
+    #[validate(custom = "validate_tab")]
+    pub tab: String,
+    #[serde(default = "default_weight")]
+    #[validate(range(min = 1))]
+    pub weight: i32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_source")]
+    #[validate(custom = "validate_source")]
+    pub source: String,
+    #[serde(default = "default_site")]
+    #[validate(length(min = 1, max = 100), custom = "validate_site")]
+    pub site: String,
+    #[validate(length(min = 1, max = 255))]
+    pub dump_path: Option<String>,
+}
+
+// Statistics of a question as seen by one scrape
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "question_snapshot")]
+pub struct QuestionSnapshot {
+    pub votes: i32,
+    pub answer: i32,
+    pub views: i32,
+    pub observed_at: DateTime<Utc>,
+}
+
+// Growth of a question between its first and last snapshot within the window
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "question_snapshot")]
+pub struct RisingQuestion {
+    pub question_id: i32,
+    pub title: String,
+    pub question_link: Option<String>,
+    pub votes: i32,
+    pub answer: i32,
+    pub views: i32,
+    pub votes_gained: i32,
+    pub answers_gained: i32,
+    pub views_gained: i32,
+    pub first_observed_at: DateTime<Utc>,
+    pub last_observed_at: DateTime<Utc>,
+}
+
+fn default_rising_hours() -> i32 {
+    24
+}
+
+fn default_rising_limit() -> i64 {
+    10
+}
+
+#[derive(Validate, Deserialize)]
+pub struct RisingQuery {
+    // questions are compared over the snapshots of the last `hours`
+    #[serde(default = "default_rising_hours")]
+    #[validate(range(min = 1, max = 720))]
+    pub hours: i32,
+    #[serde(default = "default_rising_limit")]
+    #[validate(range(min = 1, max = 100))]
+    pub limit: i64,
+}
+
+pub const QUESTION_SORTS: [&str; 4] = ["newest", "votes", "answers", "views"];
+pub const TAG_MODES: [&str; 2] = ["or", "and"];
+
+fn validate_sort(sort: &str) -> Result<(), ValidationError> {
+    if QUESTION_SORTS.contains(&sort) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("sort must be one of newest, votes, answers or views"))
+    }
+}
+
+fn validate_tag_mode(tag_mode: &str) -> Result<(), ValidationError> {
+    if TAG_MODES.contains(&tag_mode) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("tag_mode must be either or or and"))
+    }
+}
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_page_limit() -> i64 {
+    20
+}
+
+fn default_sort() -> String {
+    QUESTION_SORTS[0].to_string()
+}
+
+fn default_tag_mode() -> String {
+    TAG_MODES[0].to_string()
+}
+
+// The page is left out when serializing, page links add it themselves
+#[derive(Validate, Serialize, Deserialize)]
+pub struct QuestionQuery {
+    // bounded so the offset of the page can't overflow
+    #[serde(default = "default_page", skip_serializing)]
+    #[validate(range(min = 1, max = 100000))]
+    pub page: i64,
+    #[serde(default = "default_page_limit")]
+    #[validate(range(min = 1, max = 100))]
+    pub limit: i64,
+    #[serde(default = "default_sort")]
+    #[validate(custom = "validate_sort")]
+    pub sort: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_votes: Option<i32>,
+    // only the questions without any answer
+    #[serde(default)]
+    pub unanswered: bool,
+    // comma separated tag titles like rust,python
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    // `or` matches questions with any of the tags, `and` only those with all of them
+    #[serde(default = "default_tag_mode")]
+    #[validate(custom = "validate_tag_mode")]
+    pub tag_mode: String,
+}
+
+// Distinct tag titles of a comma separated `tag` parameter
+pub fn split_tags(tag: &Option<String>) -> Vec<String> {
+    let mut tags: Vec<String> = tag
+        .iter()
+        .flat_map(|tag| tag.split(','))
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+#[derive(Serialize)]
+pub struct QuestionPage {
+    pub questions: Vec<Questions>,
+    // questions matching the filters across all pages
+    pub total: i64,
+    pub page: i64,
+    pub limit: i64,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+}
+
+#[derive(Validate, Serialize, Deserialize)]
+pub struct SearchQuery {
+    // web search syntax, "quoted phrases", or and -excluded words are understood
+    #[serde(default)]
+    #[validate(length(min = 1, max = 200))]
+    pub q: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default = "default_tag_mode")]
+    #[validate(custom = "validate_tag_mode")]
+    pub tag_mode: String,
+    #[serde(default = "default_page", skip_serializing)]
+    #[validate(range(min = 1, max = 100000))]
+    pub page: i64,
+    #[serde(default = "default_page_limit")]
+    #[validate(range(min = 1, max = 100))]
+    pub limit: i64,
+}
+
+// The snippets are html escaped with the matched words wrapped in <mark>
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "question")]
+pub struct SearchResult {
+    pub question_id: i32,
+    pub title: String,
+    pub question_link: Option<String>,
+    pub votes: i32,
+    pub views: i32,
+    pub answer: i32,
+    pub rank: f32,
+    pub title_snippet: String,
+    pub description_snippet: String,
+}
+
+#[derive(Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    pub total: i64,
+    pub page: i64,
+    pub limit: i64,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+}
+
+// A recorded scrape run, `status` is running, succeeded or failed
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "scrape_run")]
+pub struct ScrapeRun {
+    pub run_id: i32,
+    // unset once the target was deleted
+    pub target_id: Option<i32>,
+    pub url: String,
+    pub status: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub questions_seen: i32,
+    pub questions_new: i32,
+    pub questions_updated: i32,
+    pub tags_created: i32,
+    pub details_stored: i32,
+    pub skipped: i32,
+    pub rolled_back: i32,
+    pub errors: Vec<String>,
+}
+
+fn default_scrape_run_limit() -> i64 {
+    50
+}
+
+#[derive(Validate, Deserialize)]
+pub struct ScrapeRunQuery {
+    #[serde(default = "default_scrape_run_limit")]
+    #[validate(range(min = 1, max = 500))]
+    pub limit: i64,
+}
+
+// An on-demand scrape of `pages` listing pages of a tag, whatever the scrape targets are
+#[derive(Validate, Deserialize)]
+#[validate(schema(function = "validate_create_scrape_job"))]
+pub struct CreateScrapeJob {
+    #[validate(length(min = 1, max = 30))]
+    pub tag_title: String,
+    #[validate(range(min = 1, max = 50))]
+    pub pages: i32,
+    #[serde(default = "default_tab")]
+    #[validate(custom = "validate_tab")]
+    pub tab: String,
+    #[serde(default = "default_source")]
+    #[validate(custom = "validate_source")]
+    pub source: String,
+    #[serde(default = "default_site")]
+    #[validate(length(min = 1, max = 100), custom = "validate_site")]
+    pub site: String,
+}
+
+// `status` is queued, running, succeeded or failed. The question counts come from the scrape run
+// and are only set once the job has finished
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "scrape_job")]
+pub struct ScrapeJob {
+    pub job_id: i32,
+    pub tag_title: String,
+    pub tab: String,
+    pub site: String,
+    pub source: String,
+    pub dump_path: Option<String>,
+    pub pages: i32,
+    pub status: String,
+    pub pages_done: i32,
+    pub run_id: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub questions_seen: Option<i32>,
+    pub questions_new: Option<i32>,
+    pub questions_updated: Option<i32>,
+}
+
+impl ScrapeJob {
+    // The job is scraped like a scrape target which is not stored
+    pub fn target(&self) -> ScrapeTarget {
+        ScrapeTarget {
+            target_id: 0,
+            tag_title: self.tag_title.clone(),
+            tab: self.tab.clone(),
+            weight: 1,
+            enabled: true,
+            source: self.source.clone(),
+            site: self.site.clone(),
+            dump_path: self.dump_path.clone(),
+        }
+    }
+}
+
+// A job of the background queue, `status` is queued, running, succeeded or dead
+#[derive(Serialize, Deserialize, PostgresMapper, Debug)]
+#[pg_mapper(table = "jobs")]
+pub struct Job {
+    pub job_id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    // when a queued job is due, later than its creation while it waits for a retry
+    pub run_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub locked_by: Option<String>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+pub const JOB_STATUSES: [&str; 4] = ["queued", "running", "succeeded", "dead"];
+
+fn validate_job_status(status: &str) -> Result<(), ValidationError> {
+    if JOB_STATUSES.contains(&status) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("status must be one of queued, running, succeeded or dead"))
+    }
+}
+
+fn default_job_limit() -> i64 {
+    50
+}
+
+#[derive(Validate, Deserialize)]
+pub struct JobQuery {
+    #[validate(custom = "validate_job_status")]
+    pub status: Option<String>,
+    #[validate(length(min = 1, max = 50))]
+    pub kind: Option<String>,
+    #[serde(default = "default_job_limit")]
+    #[validate(range(min = 1, max = 500))]
+    pub limit: i64,
+}
+
+// Payload of the question_detail jobs
+#[derive(Serialize, Deserialize)]
+pub struct QuestionDetailJob {
+    pub question_id: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::{CreateQuestion, CreateScrapeJob, CreateScrapeTarget, UpdateQuestion};
+
+    fn question(question_link: &str) -> CreateQuestion {
+        CreateQuestion {
+            title: "How do I exit vim?".to_string(),
+            q_description: "Asking for a friend".to_string(),
+            question_link: Some(question_link.to_string()),
+            votes: 0,
+            views: 0,
+            answer: 0,
+            stack_id: None,
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_question_link_must_be_a_web_page() {
+        assert!(question("https://stackoverflow.com/questions/11828270").validate().is_ok());
+        assert!(question("http://example.com/q").validate().is_ok());
+        assert!(question("javascript:alert(document.cookie)").validate().is_err());
+        assert!(question("JavaScript:alert(1)").validate().is_err());
+        assert!(question("data:text/html,<script>alert(1)</script>").validate().is_err());
+
+        let update = UpdateQuestion {
+            title: None,
+            q_description: None,
+            question_link: Some("javascript:alert(1)".to_string()),
+            votes: None,
+            views: None,
+            answer: None,
+            tags: None,
+        };
+        assert!(update.validate().is_err());
+    }
+
+    #[test]
+    fn test_scrape_jobs_never_read_dumps() {
+        let job = |source: &str| CreateScrapeJob {
+            tag_title: "rust".to_string(),
+            pages: 1,
+            tab: "Newest".to_string(),
+            source: source.to_string(),
+            site: "stackoverflow".to_string(),
+        };
+
+        assert!(job("html").validate().is_ok());
+        assert!(job("dump").validate().is_err());
+    }
+
+    #[test]
+    fn test_dump_path_stays_inside_the_dump_directory() {
+        let target = |dump_path: &str| CreateScrapeTarget {
+            tag_title: "rust".to_string(),
+            tab: "Newest".to_string(),
+            weight: 1,
+            enabled: true,
+            source: "dump".to_string(),
+            site: "stackoverflow".to_string(),
+            dump_path: Some(dump_path.to_string()),
+        };
+
+        assert!(target("dump.json").validate().is_ok());
+        assert!(target("./rust/dump.json").validate().is_ok());
+        assert!(target("/etc/passwd").validate().is_err());
+        assert!(target("../secrets.json").validate().is_err());
+        assert!(target("rust/../../secrets.json").validate().is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_rt::task::JoinHandle;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use deadpool_postgres::{Client, Object, Pool};
+use rand::Rng;
+use slog::{error, info, o, warn, Logger};
+use tokio::sync::watch;
+
+use crate::config::JobConfig;
+use crate::error::AppError;
+
+type JobRun = Rc<dyn Fn() -> Pin<Box<dyn Future<Output = ()>>>>;
+type WorkerRun = Box<dyn FnOnce(watch::Receiver<bool>) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+struct Job {
+    name: String,
+    schedule: Schedule,
+    jitter: Duration,
+    run: JobRun,
+}
+
+// Runs named jobs on their cron schedule. A job never overlaps with itself: the next tick is only
+// looked up once the running job has finished, ticks passing meanwhile are skipped
+pub struct Scheduler {
+    log: Logger,
+    jobs: Vec<Job>,
+    workers: Vec<(String, WorkerRun)>,
+    coordinator: Option<Rc<Coordinator>>,
+}
+
+// Lets replicas sharing a database run every tick of a job on one of them only. A tick is run by
+// the instance which holds the advisory lock of the job and claims the tick in scheduler_lease
+// first. The lock belongs to the database session, so it is released when its holder dies and the
+// next tick runs on any instance left
+struct Coordinator {
+    pool: Pool,
+    instance: String,
+}
+
+// A claimed tick, the advisory lock is held until it is released. The lock lives in the session
+// of the pooled connection, so a lease which is not released, because its job panicked or was
+// dropped, or whose unlock failed takes the connection out of the pool and closes it
+struct Lease {
+    client: Option<Client>,
+    job: String,
+}
+
+// First key of the two key advisory locks of the jobs, the second is the hashed job name
+const LEASE_LOCK: i32 = 7_305;
+
+// Stops the jobs of a started scheduler
+pub struct SchedulerHandle {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(log: Logger) -> Self {
+        Scheduler {
+            log,
+            jobs: Vec::new(),
+            workers: Vec::new(),
+            coordinator: None,
+        }
+    }
+
+    // Coordinates the jobs with the other replicas using the same database, `instance` names
+    // this one in scheduler_lease
+    pub fn coordinate(&mut self, pool: Pool, instance: &str) {
+        info!(self.log, "Scheduling as instance {}", instance);
+        self.coordinator = Some(Rc::new(Coordinator {
+            pool,
+            instance: instance.to_string(),
+        }));
+    }
+
+    // Disabled jobs are left out, an invalid cron expression is an error
+    pub fn add<F, Fut>(&mut self, name: &str, config: &JobConfig, run: F) -> Result<(), cron::error::Error>
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let schedule = Schedule::from_str(&config.cron)?;
+        if !config.enabled {
+            info!(self.log, "Job {} is disabled", name);
+            return Ok(());
+        }
+        self.jobs.push(Job {
+            name: name.to_string(),
+            schedule,
+            jitter: Duration::from_secs(config.jitter_secs),
+            run: Rc::new(move || Box::pin(run())),
+        });
+        Ok(())
+    }
+
+    // Long running tasks like queue workers, stopped together with the jobs. A worker has to
+    // return soon after the receiver it is given changes
+    pub fn add_worker<F, Fut>(&mut self, name: &str, run: F)
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.workers
+            .push((name.to_string(), Box::new(move |stopped| Box::pin(run(stopped)))));
+    }
+
+    // Every job and worker gets its own task on the current arbiter
+    pub fn start(self) -> SchedulerHandle {
+        let (shutdown, stopped) = watch::channel(false);
+        let mut tasks: Vec<JoinHandle<()>> = self
+            .jobs
+            .into_iter()
+            .map(|job| {
+                let log = self.log.new(o!("job" => job.name.clone()));
+                actix_rt::spawn(run_job(job, log, self.coordinator.clone(), stopped.clone()))
+            })
+            .collect();
+        for (name, run) in self.workers {
+            info!(self.log, "Started worker {}", name);
+            tasks.push(actix_rt::spawn(run(stopped.clone())));
+        }
+        SchedulerHandle { shutdown, tasks }
+    }
+}
+
+impl SchedulerHandle {
+    // Waits for the running jobs to finish, no job is started afterwards
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Coordinator {
+    // None when another instance runs the job right now or has run this tick already
+    async fn acquire(&self, job: &str, tick: DateTime<Utc>) -> Result<Option<Lease>, AppError> {
+        let client = self.pool.get().await?;
+        let locked: bool = client
+            .query_one("select pg_try_advisory_lock($1, hashtext($2));", &[&LEASE_LOCK, &job])
+            .await?
+            .get(0);
+        if !locked {
+            return Ok(None);
+        }
+        let lease = Lease {
+            client: Some(client),
+            job: job.to_string(),
+        };
+        let claimed = lease
+            .client()
+            .query_opt(
+                "insert into scheduler_lease (job_name,last_tick,holder) values ($1,$2,$3)
+                on conflict (job_name) do update set last_tick = excluded.last_tick, holder = excluded.holder, acquired_at = now()
+                where scheduler_lease.last_tick < excluded.last_tick returning job_name;",
+                &[&job, &tick, &self.instance],
+            )
+            .await;
+        match claimed {
+            Ok(Some(_)) => Ok(Some(lease)),
+            Ok(None) => {
+                lease.release().await?;
+                Ok(None)
+            }
+            Err(err) => {
+                lease.release().await?;
+                Err(AppError::from(err))
+            }
+        }
+    }
+}
+
+impl Lease {
+    fn client(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+
+    async fn release(mut self) -> Result<(), AppError> {
+        self.client()
+            .execute("select pg_advisory_unlock($1, hashtext($2));", &[&LEASE_LOCK, &self.job])
+            .await?;
+        // unlocked, the connection goes back to the pool
+        self.client.take();
+        Ok(())
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            drop(Object::take(client));
+        }
+    }
+}
+
+async fn run_job(
+    job: Job,
+    log: Logger,
+    coordinator: Option<Rc<Coordinator>>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    info!(log, "Scheduled job {}", job.name);
+    while let Some(next) = job.schedule.upcoming(Utc).next() {
+        let mut delay = (next - Utc::now()).to_std().unwrap_or_default();
+        if !job.jitter.is_zero() {
+            delay += rand::thread_rng().gen_range(Duration::ZERO..=job.jitter);
+        }
+        tokio::select! {
+            _ = actix_rt::time::sleep(delay) => {}
+            _ = stopped.changed() => break,
+        }
+        if *stopped.borrow() {
+            break;
+        }
+
+        // a tick which can't be claimed is left to the other instances
+        let lease = match &coordinator {
+            Some(coordinator) => match coordinator.acquire(&job.name, next).await {
+                Ok(Some(lease)) => Some(lease),
+                Ok(None) => {
+                    info!(log, "Tick {} of job {} runs on another instance", next, job.name);
+                    continue;
+                }
+                Err(err) => {
+                    error!(log, "Claiming tick {} of job {} failed: {:?}", next, job.name, err);
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        (job.run)().await;
+        if let Some(lease) = lease {
+            if let Err(err) = lease.release().await {
+                error!(log, "Releasing the lock of job {} failed: {:?}", job.name, err);
+            }
+        }
+        let skipped = job.schedule.after(&next).take_while(|tick| *tick < Utc::now()).count();
+        if skipped > 0 {
+            warn!(log, "Job {} ran past {} of its ticks, they were skipped", job.name, skipped);
+        }
+    }
+    info!(log, "Stopped job {}", job.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use slog::{o, Discard, Logger};
+
+    use super::{Coordinator, Scheduler, LEASE_LOCK};
+    use crate::config::{test_pool, JobConfig};
+
+    fn every_second() -> JobConfig {
+        JobConfig {
+            cron: "* * * * * * *".to_string(),
+            jitter_secs: 0,
+            enabled: true,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_slow_job_does_not_overlap_and_stop_waits_for_it() {
+        let running = Rc::new(Cell::new(0));
+        let most_running = Rc::new(Cell::new(0));
+        let runs = Rc::new(Cell::new(0));
+
+        let mut scheduler = Scheduler::new(Logger::root(Discard, o!()));
+        let (job_running, job_most_running, job_runs) = (running.clone(), most_running.clone(), runs.clone());
+        scheduler
+            .add("slow", &every_second(), move || {
+                let (running, most_running, runs) =
+                    (job_running.clone(), job_most_running.clone(), job_runs.clone());
+                async move {
+                    running.set(running.get() + 1);
+                    most_running.set(most_running.get().max(running.get()));
+                    actix_rt::time::sleep(Duration::from_millis(1500)).await;
+                    running.set(running.get() - 1);
+                    runs.set(runs.get() + 1);
+                }
+            })
+            .unwrap();
+
+        let handle = scheduler.start();
+        actix_rt::time::sleep(Duration::from_millis(3500)).await;
+        handle.stop().await;
+
+        assert_eq!(most_running.get(), 1);
+        assert_eq!(running.get(), 0, "stop returns once the running job finished");
+        assert!(runs.get() >= 1 && runs.get() <= 2, "ran {} times", runs.get());
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_and_disabled_jobs() {
+        let mut scheduler = Scheduler::new(Logger::root(Discard, o!()));
+        let invalid = JobConfig {
+            cron: "every minute".to_string(),
+            ..every_second()
+        };
+        assert!(scheduler.add("invalid", &invalid, || async {}).is_err());
+
+        let disabled = JobConfig {
+            enabled: false,
+            ..every_second()
+        };
+        scheduler.add("disabled", &disabled, || async {}).unwrap();
+        assert!(scheduler.jobs.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_stop_waits_for_workers() {
+        let stopped = Rc::new(Cell::new(false));
+        let mut scheduler = Scheduler::new(Logger::root(Discard, o!()));
+        let worker_stopped = stopped.clone();
+        scheduler.add_worker("worker", move |mut shutdown| async move {
+            let _ = shutdown.changed().await;
+            worker_stopped.set(true);
+        });
+
+        let handle = scheduler.start();
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(!stopped.get());
+        handle.stop().await;
+        assert!(stopped.get());
+    }
+
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_one_instance_per_tick() {
+        let pool = test_pool();
+        let instance = |name: &str| Coordinator {
+            pool: pool.clone(),
+            instance: name.to_string(),
+        };
+        let (first, second) = (instance("first"), instance("second"));
+        let job = format!("test-{:08x}", rand::random::<u32>());
+        let tick = Utc.timestamp(1_600_000_000, 0);
+
+        let lease = first.acquire(&job, tick).await.unwrap().unwrap();
+        assert!(second.acquire(&job, tick).await.unwrap().is_none(), "the lock is held");
+        lease.release().await.unwrap();
+        assert!(second.acquire(&job, tick).await.unwrap().is_none(), "the tick is claimed");
+        let next = tick + chrono::Duration::seconds(50);
+        let lease = second.acquire(&job, next).await.unwrap().unwrap();
+
+        // a lease dropped without being released, like the one of a panicking job, closes its
+        // connection, which releases the lock. Its tick stays claimed
+        drop(lease);
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        let client = pool.get().await.unwrap();
+        let held: i64 = client
+            .query_one(
+                "select count(*) from pg_locks where locktype = 'advisory' and classid = $1::integer::oid and objid = hashtext($2)::oid;",
+                &[&LEASE_LOCK, &job],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(held, 0, "the lock is released");
+        drop(client);
+        assert!(first.acquire(&job, next).await.unwrap().is_none());
+        let next = next + chrono::Duration::seconds(50);
+        first.acquire(&job, next).await.unwrap().unwrap().release().await.unwrap();
+
+        let client = pool.get().await.unwrap();
+        client
+            .execute("delete from scheduler_lease where job_name = $1;", &[&job])
+            .await
+            .unwrap();
+    }
+}
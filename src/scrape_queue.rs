@@ -0,0 +1,186 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_postgres::{Client, Pool};
+use slog::{error, info, o, warn, Logger};
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::broadcast::Broadcaster;
+use crate::config::ScraperConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::fetcher::Fetcher;
+use crate::handlers::run_scrape;
+use crate::models::{CreateScrapeJob, ScrapeJob, ScrapeSummary};
+use crate::source::source_for;
+
+// SSE event carrying the current state of a scrape job
+pub const SCRAPE_JOB_EVENT: &str = "scrape_job";
+
+// A running job is leased for this long and renewed three times per lease, a job whose worker
+// died is taken over once it runs out
+const SCRAPE_JOB_LEASE: Duration = Duration::from_secs(60);
+
+// On-demand scrape jobs. They are stored in the scrape_job table and run one after another by
+// the worker, which is woken up whenever a job is enqueued
+#[derive(Default)]
+pub struct ScrapeQueue {
+    enqueued: Notify,
+}
+
+// Everything a scrape job needs besides the job itself. `worker` names the worker holding the
+// lease of a job, unique across instances
+pub struct ScrapeWorker {
+    pub pool: Pool,
+    pub log: Logger,
+    pub worker: String,
+    pub config: ScraperConfig,
+    pub fetcher: Rc<Fetcher>,
+    pub broadcaster: Arc<Broadcaster>,
+}
+
+impl ScrapeQueue {
+    pub async fn enqueue(&self, client: &Client, job: &CreateScrapeJob) -> Result<ScrapeJob, AppError> {
+        let job = db::create_scrape_job(client, job).await?;
+        self.enqueued.notify_one();
+        Ok(job)
+    }
+
+    // Runs the queued jobs until `stopped` changes, jobs queued before the start are picked up
+    // too. A running job is finished before returning. While idle, jobs abandoned by a dead
+    // worker are looked for once per lease
+    pub async fn work(self: Arc<Self>, worker: ScrapeWorker, mut stopped: watch::Receiver<bool>) {
+        while !*stopped.borrow() {
+            let claimed = match worker.pool.get().await {
+                Ok(client) => db::claim_scrape_job(&client, &worker.worker, SCRAPE_JOB_LEASE.as_secs_f64()).await,
+                Err(err) => Err(AppError::from(err)),
+            };
+            match claimed {
+                Ok(Some(job)) => worker.run(job).await,
+                Ok(None) => {
+                    tokio::select! {
+                        _ = self.enqueued.notified() => {}
+                        _ = actix_rt::time::sleep(SCRAPE_JOB_LEASE) => {}
+                        _ = stopped.changed() => {}
+                    }
+                }
+                Err(err) => {
+                    error!(worker.log, "Claiming a scrape job failed: {:?}", err);
+                    tokio::select! {
+                        _ = actix_rt::time::sleep(Duration::from_secs(5)) => {}
+                        _ = stopped.changed() => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ScrapeWorker {
+    async fn run(&self, job: ScrapeJob) {
+        let log = self.log.new(o!("scrape_job" => job.job_id));
+        if let Some(run_id) = job.run_id {
+            warn!(log, "Taking over scrape job {}, its worker stopped renewing the lease", job.job_id);
+            let abandoned = match self.pool.get().await {
+                Ok(client) => db::fail_scrape_run(&client, run_id, "abandoned by its worker").await,
+                Err(err) => Err(AppError::from(err)),
+            };
+            if let Err(err) = abandoned {
+                error!(log, "Failing the abandoned run {} failed: {:?}", run_id, err);
+            }
+        }
+        info!(log, "Running scrape job {} for {} pages of {}", job.job_id, job.pages, job.tag_title);
+        let error = match self.scrape(&log, &job).await {
+            Ok(summary) => {
+                info!(
+                    log,
+                    "Scrape job {} done: {} seen, {} new, {} updated",
+                    job.job_id,
+                    summary.questions_seen,
+                    summary.questions_new,
+                    summary.questions_updated
+                );
+                None
+            }
+            Err(err) => {
+                warn!(log, "Scrape job {} failed: {:?}", job.job_id, err);
+                Some(err.cause.clone().unwrap_or_else(|| err.to_string()))
+            }
+        };
+
+        let finished = match self.pool.get().await {
+            Ok(client) => match db::finish_scrape_job(&client, job.job_id, &self.worker, error.as_deref()).await {
+                Ok(()) => self.report(&client, job.job_id).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(AppError::from(err)),
+        };
+        if let Err(err) = finished {
+            error!(log, "Finishing scrape job {} failed: {:?}", job.job_id, err);
+        }
+    }
+
+    // Walks the requested pages whatever the scraper config says, also past questions which are
+    // stored already. The fetched pages are reported
+    // and the lease is renewed while the run goes on
+    async fn scrape(&self, log: &Logger, job: &ScrapeJob) -> Result<ScrapeSummary, AppError> {
+        let mut client = self.pool.get().await?;
+        let progress_client = self.pool.get().await?;
+        let target = job.target();
+        let config = ScraperConfig {
+            total: usize::MAX,
+            max_pages: job.pages as usize,
+            stop_at_known: false,
+            ..self.config.clone()
+        };
+
+        let url = source_for(&target, &config).location();
+        let run_id = db::start_scrape_run(&client, None, &url).await?;
+        db::set_scrape_job_run(&client, job.job_id, &self.worker, run_id).await?;
+        self.report(&client, job.job_id).await?;
+
+        let (progress, mut pages) = mpsc::unbounded_channel();
+        let report_progress = async {
+            let mut renew = actix_rt::time::interval(SCRAPE_JOB_LEASE / 3);
+            loop {
+                tokio::select! {
+                    page = pages.recv() => {
+                        // the run is over and has dropped the sender
+                        let page = match page {
+                            Some(page) => page,
+                            None => break,
+                        };
+                        let reported =
+                            match db::set_scrape_job_progress(&progress_client, job.job_id, &self.worker, page as i32).await {
+                                Ok(()) => self.report(&progress_client, job.job_id).await,
+                                Err(err) => Err(err),
+                            };
+                        if let Err(err) = reported {
+                            warn!(log, "Reporting the progress of scrape job {} failed: {:?}", job.job_id, err);
+                        }
+                    }
+                    _ = renew.tick() => {
+                        let lease_secs = SCRAPE_JOB_LEASE.as_secs_f64();
+                        if let Err(err) = db::renew_scrape_job(&progress_client, job.job_id, &self.worker, lease_secs).await {
+                            warn!(log, "Renewing the lease of scrape job {} failed: {:?}", job.job_id, err);
+                        }
+                    }
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(
+            run_scrape(&mut client, log, &config, &self.fetcher, &target, run_id, Some(progress)),
+            report_progress
+        );
+        result
+    }
+
+    // Pushes the current state of the job to the SSE clients
+    async fn report(&self, client: &Client, job_id: i32) -> Result<(), AppError> {
+        let job = db::get_scrape_job(client, job_id).await?;
+        let msg = serde_json::to_string(&job).unwrap();
+        self.broadcaster.broadcast_event(SCRAPE_JOB_EVENT, &msg).await;
+        Ok(())
+    }
+}
@@ -1,102 +1,513 @@
-use crate::models::{ScrapedQuestion, ScraperResult};
+use crate::error::ScrapeError;
+use crate::fetcher::Fetcher;
+use crate::models::{
+    QuestionDetail, ScrapeTarget, ScrapedAnswer, ScrapedQuestion, ScraperResult, SkippedPost,
+};
 use rand::seq::SliceRandom;
-use regex::{Captures, Regex};
+use reqwest::Url;
 use select::document::Document;
-use select::predicate::{Class, Name, Predicate};
-use slog::{info, Logger};
+use select::node::Node;
+use select::predicate::{Attr, Class, Name, Predicate};
+use slog::{info, warn, Logger};
 use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc::UnboundedSender;
 
-#[warn(unused_imports, dead_code)]
-fn views_count(views: &str) -> i32 {
-    let re = Regex::new("(or|e)").unwrap();
-    let result = re.replace_all(views, |cap: &Captures| {
-        match &cap[0] {
-            "or" => "str",
-            "e" => "er",
-            _ => panic!("We should never get here"),
+// Stackoverflow abbreviates view counts, this turns "1,234", "12k" or "1.2m" into plain numbers
+pub fn parse_views(views: &str) -> Result<i32, ScrapeError> {
+    let bad_number = || ScrapeError::BadNumber {
+        field: "views",
+        value: views.to_string(),
+    };
+    let cleaned = views
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ',')
+        .collect::<String>()
+        .to_lowercase();
+    let (number, multiplier) = if let Some(number) = cleaned.strip_suffix('k') {
+        (number, 1_000.0)
+    } else if let Some(number) = cleaned.strip_suffix('m') {
+        (number, 1_000_000.0)
+    } else {
+        (cleaned.as_str(), 1.0)
+    };
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return Err(bad_number());
+    }
+    let count = (number.parse::<f64>().map_err(|_| bad_number())? * multiplier).round();
+    if count > i32::MAX as f64 {
+        return Err(bad_number());
+    }
+    Ok(count as i32)
+}
+
+// Stackoverflow accepts at most 50 questions per listing page
+pub const PAGE_SIZE: usize = 50;
+
+// Decides how far a scrape run walks the paginated listing
+pub struct PageOptions {
+    // total number of questions to collect across all pages
+    pub total: usize,
+    // upper bound on the number of listing pages fetched
+    pub max_pages: usize,
+    // walking stops once one of these questions shows up, leave empty to never stop early
+    pub known_ids: HashSet<i32>,
+    // receives the number of every page once it is fetched
+    pub progress: Option<UnboundedSender<usize>>,
+}
+
+impl PageOptions {
+    pub fn page_fetched(&self, page: usize) {
+        if let Some(progress) = &self.progress {
+            // nobody following the run is fine
+            let _ = progress.send(page);
         }
-        .to_string()
-    });
-    result.into_owned().parse::<i32>().unwrap()
+    }
+}
+
+// Listing url of the given page, the page size is always the maximum to save requests
+pub fn page_url(url: &str, page: usize) -> String {
+    let mut url = Url::parse(url).unwrap();
+    url.query_pairs_mut()
+        .append_pair("page", &page.to_string())
+        .append_pair("pagesize", &PAGE_SIZE.to_string());
+    url.to_string()
+}
+
+fn parse_number(field: &'static str, value: &str) -> Result<i32, ScrapeError> {
+    value
+        .trim()
+        .parse::<i32>()
+        .map_err(|_| ScrapeError::BadNumber {
+            field,
+            value: value.to_string(),
+        })
+}
+
+fn parse_summary(node: Node) -> Result<ScrapedQuestion, ScrapeError> {
+    let question = node
+        .select(Class("s-post-summary--content-excerpt"))
+        .next()
+        .ok_or(ScrapeError::MissingElement("post excerpt"))?
+        .text()
+        .trim()
+        .to_string();
+    let title_element = node
+        .select(Class("s-post-summary--content-title").child(Name("a")))
+        .next()
+        .ok_or(ScrapeError::MissingElement("post title"))?;
+    let title = title_element.text().trim().to_string();
+    let question_link = title_element
+        .attr("href")
+        .ok_or(ScrapeError::MissingElement("post link"))?;
+    let stats = node
+        .select(Class("s-post-summary--stats-item-number"))
+        .map(|stat| stat.text().trim().to_string())
+        .collect::<Vec<_>>();
+    if stats.len() != 3 {
+        return Err(ScrapeError::LayoutChanged(format!(
+            "expected votes, answers and views stats, found {} stats",
+            stats.len()
+        )));
+    }
+    let tags_vec: HashSet<String> = node
+        .select(Class("post-tag"))
+        .map(|tag| tag.text())
+        .collect();
+    let post_id = node
+        .attr("data-post-id")
+        .ok_or(ScrapeError::MissingElement("post id"))?;
+
+    Ok(ScrapedQuestion {
+        title,
+        q_description: question,
+        question_link: question_link.to_owned(),
+        votes: parse_number("votes", &stats[0])?,
+        views: parse_views(&stats[2])?,
+        stack_id: parse_number("post id", post_id)?,
+        tags: tags_vec,
+        answer: parse_number("answers", &stats[1])?,
+    })
 }
 
+// One parsed listing page, summaries counts every post summary including the skipped ones
+pub struct ListingPage {
+    pub questions: Vec<ScrapedQuestion>,
+    pub skipped: Vec<SkippedPost>,
+    pub summaries: usize,
+}
+
+// Parses a listing page without touching the network, post summaries which can't be parsed are skipped
+pub fn parse_listing(html: &str) -> Result<ListingPage, ScrapeError> {
+    let document = Document::from(html);
+    if document.select(Attr("id", "questions")).next().is_none() {
+        return Err(ScrapeError::LayoutChanged(
+            "no question list on the page".to_string(),
+        ));
+    }
+
+    let mut page = ListingPage {
+        questions: Vec::new(),
+        skipped: Vec::new(),
+        summaries: 0,
+    };
+    for node in document.select(Class("s-post-summary")) {
+        page.summaries += 1;
+        match parse_summary(node) {
+            Ok(question) => page.questions.push(question),
+            Err(err) => page.skipped.push(SkippedPost {
+                post_id: node.attr("data-post-id").map(str::to_owned),
+                reason: err.to_string(),
+            }),
+        }
+    }
+    Ok(page)
+}
+
+// Walks the listing pages until the requested total, a known question or the last page is reached.
+// Post summaries which can't be parsed are skipped and reported instead of failing the whole run
 pub async fn hacker_news(
     log: &Logger,
+    fetcher: &Fetcher,
     url: &str,
-    count: usize,
-) -> Result<ScraperResult, reqwest::Error> {
-    info!(log, "check 1");
-    let resp = reqwest::get(url).await?;
-    // info!(log,"body = {:?}", resp.text().await?);
-    // assert!(resp.status().is_success());
-    let document = Document::from(&*resp.text().await?);
-
+    options: &PageOptions,
+) -> Result<ScraperResult, ScrapeError> {
     let mut res = Vec::new();
     let mut tag_set = HashMap::new();
+    let mut skipped = Vec::new();
 
-    for node in document.select(Class("s-post-summary")).take(count) {
-        let question = node
-            .select(Class("s-post-summary--content-excerpt"))
-            .next()
-            .unwrap()
-            .text();
-        let title_element = node
-            .select(Class("s-post-summary--content-title").child(Name("a")))
-            .next()
-            .unwrap();
-        let title = title_element.text();
-        let question_link = title_element.attr("href").unwrap();
-        let stats = node
-            .select(Class("s-post-summary--stats-item-number"))
-            .map(|stat| stat.text())
-            .collect::<Vec<_>>();
-        let votes = &stats[0];
-        let answer = &stats[1];
-        let views = &stats[2];
-        let tags_vec:HashSet<String> = node
-            .select(Class("post-tag"))
-            .map(|tag| tag.text())
-            .collect();
-        for tag in &tags_vec {
-            *tag_set.entry(tag.clone()).or_insert(0) += 1;
+    'pages: for page in 1..=options.max_pages {
+        let page_url = page_url(url, page);
+        info!(log, "Fetching page  => {}", &page_url);
+        let listing = parse_listing(&fetcher.get_text(log, &page_url).await?)?;
+        options.page_fetched(page);
+
+        for post in &listing.skipped {
+            warn!(log, "Skipping post summary {:?}: {}", post.post_id, post.reason);
+        }
+        skipped.extend(listing.skipped);
+
+        for question in listing.questions {
+            if options.known_ids.contains(&question.stack_id) {
+                info!(log, "Reached already known question {}", question.stack_id);
+                break 'pages;
+            }
+            for tag in &question.tags {
+                *tag_set.entry(tag.clone()).or_insert(0) += 1;
+            }
+            res.push(question);
+            if res.len() >= options.total {
+                break 'pages;
+            }
         }
 
-        let post_id = node.attr("data-post-id").unwrap();
-        // let mut tags=Vec::new();
-        // let tag_element = node
-        //     .select(Class("tags").child(Class("mt0")));
-        // for tag in tag_element{
-        //     let tag_text = tag.text();
-        //     if !tags.contains(&tag_text){
-        //         tags.push(tag_text);
-        //     }
-        // }
-        let question = ScrapedQuestion {
-            title,
-            q_description: question,
-            question_link: question_link.to_owned(),
-            votes: votes.parse::<i32>().unwrap(),
-            views: views.to_owned(),
-            stack_id: post_id.parse::<i32>().unwrap(),
-            tags:tags_vec,
-            answer: answer.parse::<i32>().unwrap(),
-        };
-        res.push(question);
+        // a page which is not full is the last one
+        if listing.summaries < PAGE_SIZE {
+            break;
+        }
     }
     Ok(ScraperResult {
         questions: res,
         unique_tags: tag_set,
+        skipped,
     })
 }
 
-// Getting random tag
-pub fn get_random_url(log: &Logger) -> String {
-    let default_tags = vec!["python", "rust", "c#", "android", "html", "javascript"];
-    let random_tag = default_tags.choose(&mut rand::thread_rng()).unwrap();
-    let url = format!(
-        "https://stackoverflow.com/questions/tagged/{}?tab=Newest",
-        random_tag
-    );
-    info!(log, "Url           => {}", &url);
+// Stack Exchange sites live either on their own domain or below stackexchange.com.
+// Site names are validated to lowercase words joined by dashes, so the url always parses
+pub fn site_url(site: &str) -> Url {
+    let host = match site {
+        "stackoverflow" | "serverfault" | "superuser" | "askubuntu" | "stackapps" => {
+            format!("{}.com", site)
+        }
+        "mathoverflow" => "mathoverflow.net".to_string(),
+        _ => format!("{}.stackexchange.com", site),
+    };
+    Url::parse(&format!("https://{}/", host)).unwrap()
+}
+
+// Question links on the listing pages are relative to the site
+pub fn question_url(site: &str, question_link: &str) -> Result<String, ScrapeError> {
+    site_url(site)
+        .join(question_link)
+        .map(|url| url.to_string())
+        .map_err(|_| ScrapeError::LayoutChanged(format!("bad question link {}", question_link)))
+}
+
+fn parse_answer(node: Node) -> Result<ScrapedAnswer, ScrapeError> {
+    let answer_id = node
+        .attr("data-answerid")
+        .ok_or(ScrapeError::MissingElement("answer id"))?;
+    let score = node
+        .attr("data-score")
+        .ok_or(ScrapeError::MissingElement("answer score"))?;
+    let body_html = node
+        .select(Class("js-post-body"))
+        .next()
+        .ok_or(ScrapeError::MissingElement("answer body"))?
+        .inner_html();
+
+    Ok(ScrapedAnswer {
+        stack_answer_id: parse_number("answer id", answer_id)?,
+        body_html: body_html.trim().to_string(),
+        score: parse_number("answer score", score)?,
+        is_accepted: node.is(Class("accepted-answer")),
+    })
+}
+
+// Full body and all answers of a question page, the accepted answer is flagged
+pub fn parse_question_detail(html: &str) -> Result<QuestionDetail, ScrapeError> {
+    let document = Document::from(html);
+    let body_html = document
+        .select(Attr("id", "question").descendant(Class("js-post-body")))
+        .next()
+        .ok_or(ScrapeError::MissingElement("question body"))?
+        .inner_html();
+    let answers = document
+        .select(Attr("id", "answers").descendant(Class("answer")))
+        .map(parse_answer)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(QuestionDetail {
+        body_html: body_html.trim().to_string(),
+        answers,
+    })
+}
+
+pub async fn fetch_question_detail(
+    log: &Logger,
+    fetcher: &Fetcher,
+    url: &str,
+) -> Result<QuestionDetail, ScrapeError> {
+    info!(log, "Fetching detail => {}", url);
+    parse_question_detail(&fetcher.get_text(log, url).await?)
+}
+
+// Getting a random scrape target, targets with a higher weight are picked more often
+pub fn get_random_target<'a>(log: &Logger, targets: &'a [ScrapeTarget]) -> Option<&'a ScrapeTarget> {
+    let target = targets
+        .choose_weighted(&mut rand::thread_rng(), |target| target.weight)
+        .ok()?;
+    info!(log, "Target        => {} on {} ({})", target.tag_title, target.site, target.source);
+    Some(target)
+}
+
+// Listing page of a tag, the tag is pushed as a path segment so tags like c# are escaped
+pub fn target_url(target: &ScrapeTarget) -> String {
+    let mut url = site_url(&target.site).join("questions/tagged/").unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .pop_if_empty()
+        .push(&target.tag_title);
+    url.query_pairs_mut().append_pair("tab", &target.tab);
     url.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_random_target, page_url, parse_listing, parse_question_detail, parse_views,
+        question_url, target_url,
+    };
+    use crate::error::ScrapeError;
+    use std::collections::HashSet;
+    use crate::models::ScrapeTarget;
+    use slog::{o, Discard, Logger};
+
+    fn target(tag_title: &str, tab: &str, weight: i32) -> ScrapeTarget {
+        ScrapeTarget {
+            target_id: 1,
+            tag_title: tag_title.to_string(),
+            tab: tab.to_string(),
+            weight,
+            enabled: true,
+            source: "html".to_string(),
+            site: "stackoverflow".to_string(),
+            dump_path: None,
+        }
+    }
+
+    #[test]
+    fn test_target_url_escapes_tag() {
+        assert_eq!(
+            target_url(&target("c#", "Votes", 1)),
+            "https://stackoverflow.com/questions/tagged/c%23?tab=Votes",
+            "Tag should be escaped and tab appended"
+        );
+        let mut target = target("bash", "Newest", 1);
+        target.site = "unix".to_string();
+        assert_eq!(
+            target_url(&target),
+            "https://unix.stackexchange.com/questions/tagged/bash?tab=Newest",
+            "Other sites should live below stackexchange.com"
+        );
+    }
+
+    #[test]
+    fn test_random_target_without_targets() {
+        let log = Logger::root(Discard, o!());
+        assert!(get_random_target(&log, &[]).is_none(), "No target without targets");
+    }
+
+    #[test]
+    fn test_random_target_respects_weight() {
+        let log = Logger::root(Discard, o!());
+        let targets = [target("rust", "Newest", 1), target("python", "Newest", 0)];
+        for _ in 0..20 {
+            assert_eq!(
+                get_random_target(&log, &targets).unwrap().tag_title,
+                "rust",
+                "Zero weight target should never be picked"
+            );
+        }
+    }
+
+    #[test]
+    fn test_page_url_keeps_tab() {
+        assert_eq!(
+            page_url("https://stackoverflow.com/questions/tagged/rust?tab=Newest", 3),
+            "https://stackoverflow.com/questions/tagged/rust?tab=Newest&page=3&pagesize=50",
+            "Page and page size should be appended"
+        );
+    }
+
+    #[test]
+    fn test_parse_views() {
+        assert_eq!(parse_views("17").unwrap(), 17);
+        assert_eq!(parse_views("1,234").unwrap(), 1234);
+        assert_eq!(parse_views("12k").unwrap(), 12_000);
+        assert_eq!(parse_views("1.2k").unwrap(), 1_200);
+        assert_eq!(parse_views(" 1.2M ").unwrap(), 1_200_000);
+        assert_eq!(parse_views("3m").unwrap(), 3_000_000);
+    }
+
+    #[test]
+    fn test_parse_views_rejects_garbage() {
+        for views in ["", "k", "1.2b", "-5", "nan", "1e3", "9999m"] {
+            assert!(parse_views(views).is_err(), "{:?} should not parse", views);
+        }
+    }
+
+    #[test]
+    fn test_question_url() {
+        assert_eq!(
+            question_url("stackoverflow", "/questions/42/how-to-borrow").unwrap(),
+            "https://stackoverflow.com/questions/42/how-to-borrow"
+        );
+        assert_eq!(
+            question_url("serverfault", "/questions/42").unwrap(),
+            "https://serverfault.com/questions/42"
+        );
+        assert_eq!(
+            question_url("superuser", "https://stackoverflow.com/questions/42").unwrap(),
+            "https://stackoverflow.com/questions/42",
+            "Absolute links should be kept"
+        );
+    }
+
+    #[test]
+    fn test_parse_question_detail() {
+        let detail = parse_question_detail(include_str!("../tests/fixtures/question_detail.html")).unwrap();
+
+        assert!(detail.body_html.starts_with("<p>What is the idiomatic way"));
+        assert!(!detail.body_html.contains("into_bytes?"), "Comments are not part of the body");
+        assert_eq!(detail.answers.len(), 2);
+        assert_eq!(detail.answers[0].stack_answer_id, 74391750);
+        assert_eq!(detail.answers[0].score, 1500);
+        assert!(detail.answers[0].is_accepted, "Accepted answer should be flagged");
+        assert!(detail.answers[0].body_html.contains("s.into_bytes()"));
+        assert_eq!(detail.answers[1].stack_answer_id, 74391799);
+        assert_eq!(detail.answers[1].score, -2);
+        assert!(!detail.answers[1].is_accepted);
+        assert_eq!(detail.answers[1].body_html, "<p>Loop over the chars and push each one.</p>");
+    }
+
+    #[test]
+    fn test_parse_listing() {
+        let page = parse_listing(include_str!("../tests/fixtures/listing_rust_newest.html")).unwrap();
+
+        assert_eq!(page.summaries, 3);
+        assert!(page.skipped.is_empty());
+        let question = &page.questions[0];
+        assert_eq!(question.stack_id, 74391825);
+        assert_eq!(
+            question.title,
+            "Why does the borrow checker reject this closure & how do I fix it?"
+        );
+        assert_eq!(
+            question.question_link,
+            "/questions/74391825/why-does-the-borrow-checker-reject-this-closure"
+        );
+        assert!(question.q_description.starts_with("I have a closure that captures"));
+        assert!(question.q_description.ends_with("more than once …"));
+        assert_eq!(question.votes, 2);
+        assert_eq!(question.answer, 1);
+        assert_eq!(question.views, 1200);
+        let tags = ["rust", "closures", "borrow-checker"].map(String::from);
+        assert_eq!(question.tags, HashSet::from(tags));
+
+        let question = &page.questions[1];
+        assert_eq!(question.title, "tokio::spawn requires 'static lifetime");
+        assert_eq!(question.votes, -1);
+        assert_eq!(question.answer, 0);
+        assert_eq!(question.views, 17);
+
+        let question = &page.questions[2];
+        assert_eq!(question.q_description, "What is the idiomatic way to go from a String to a Vec<u8>?");
+        assert_eq!(question.votes, 1024);
+        assert_eq!(question.views, 2_300_000);
+        assert_eq!(question.tags, HashSet::from(["rust".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_listing_skips_malformed_summaries() {
+        let page = parse_listing(include_str!("../tests/fixtures/listing_malformed.html")).unwrap();
+
+        assert_eq!(page.summaries, 4);
+        assert_eq!(page.questions.len(), 1);
+        assert_eq!(page.questions[0].stack_id, 74391001);
+        assert_eq!(
+            page.questions[0].tags,
+            HashSet::from(["c#".to_string(), "linq".to_string()])
+        );
+
+        let skipped = page
+            .skipped
+            .iter()
+            .map(|post| (post.post_id.as_deref(), post.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            [
+                (Some("74391002"), "missing element: post title"),
+                (Some("74391003"), "bad number in votes: \"n/a\""),
+                (
+                    None,
+                    "layout changed: expected votes, answers and views stats, found 2 stats"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_listing_without_questions() {
+        let page = parse_listing(include_str!("../tests/fixtures/listing_empty.html")).unwrap();
+
+        assert_eq!(page.summaries, 0);
+        assert!(page.questions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_listing_layout_changed() {
+        let result = parse_listing(include_str!("../tests/fixtures/listing_layout_changed.html"));
+
+        assert!(
+            matches!(result, Err(ScrapeError::LayoutChanged(_))),
+            "Missing question list should be reported as a layout change"
+        );
+    }
+
+    #[test]
+    fn test_parse_question_detail_without_body() {
+        assert!(parse_question_detail("<html><body></body></html>").is_err());
+    }
+}
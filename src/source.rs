@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use slog::{info, Logger};
+
+use crate::config::ScraperConfig;
+use crate::error::ScrapeError;
+use crate::fetcher::Fetcher;
+use crate::models::{ScrapeTarget, ScrapedQuestion, ScraperResult};
+use crate::scraper::{hacker_news, question_url, target_url, PageOptions};
+use crate::stackexchange::StackExchangeApiSource;
+
+// Anything questions can be collected from. Every scrape target picks its source by name
+#[async_trait]
+pub trait QuestionSource: Send + Sync {
+    // Where the questions come from, shown in the logs and scrape summaries
+    fn location(&self) -> String;
+
+    async fn fetch(
+        &self,
+        log: &Logger,
+        fetcher: &Fetcher,
+        options: &PageOptions,
+    ) -> Result<ScraperResult, ScrapeError>;
+}
+
+pub fn source_for(target: &ScrapeTarget, config: &ScraperConfig) -> Box<dyn QuestionSource> {
+    match (target.source.as_str(), &target.dump_path) {
+        ("dump", Some(path)) => Box::new(JsonDumpSource {
+            dir: PathBuf::from(&config.dump_dir),
+            path: path.clone(),
+        }),
+        ("api", _) => Box::new(StackExchangeApiSource::new(target, config)),
+        _ => Box::new(HtmlSource {
+            site: target.site.clone(),
+            url: target_url(target),
+        }),
+    }
+}
+
+// Counts how often every tag occurs in the questions
+pub fn count_tags(questions: &[ScrapedQuestion]) -> HashMap<String, i32> {
+    let mut tag_set = HashMap::new();
+    for tag in questions.iter().flat_map(|question| &question.tags) {
+        *tag_set.entry(tag.clone()).or_insert(0) += 1;
+    }
+    tag_set
+}
+
+// The tagged listing pages of stackoverflow or any other Stack Exchange site sharing its layout
+pub struct HtmlSource {
+    pub site: String,
+    pub url: String,
+}
+
+#[async_trait]
+impl QuestionSource for HtmlSource {
+    fn location(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn fetch(
+        &self,
+        log: &Logger,
+        fetcher: &Fetcher,
+        options: &PageOptions,
+    ) -> Result<ScraperResult, ScrapeError> {
+        let mut result = hacker_news(log, fetcher, &self.url, options).await?;
+        // links are stored absolute, so questions of different sites stay apart
+        for question in &mut result.questions {
+            question.question_link = question_url(&self.site, &question.question_link)?;
+        }
+        Ok(result)
+    }
+}
+
+// A local json file holding an array of questions in the shape of `ScrapedQuestion`. The path is
+// relative to the dump directory, the file is only read when it is inside of it
+pub struct JsonDumpSource {
+    pub dir: PathBuf,
+    pub path: String,
+}
+
+impl JsonDumpSource {
+    // Links pointing out of the directory are followed before the check, so they can't escape it
+    async fn resolve(&self) -> Result<PathBuf, ScrapeError> {
+        let io_error = |err: std::io::Error| ScrapeError::Io(format!("{}: {}", self.path, err));
+        let dir = tokio::fs::canonicalize(&self.dir).await.map_err(io_error)?;
+        let path = tokio::fs::canonicalize(self.dir.join(&self.path)).await.map_err(io_error)?;
+        if path.starts_with(&dir) {
+            Ok(path)
+        } else {
+            Err(ScrapeError::Io(format!("{}: outside of the dump directory", self.path)))
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Vec<ScrapedQuestion>, ScrapeError> {
+        serde_json::from_str(json).map_err(|err| ScrapeError::LayoutChanged(err.to_string()))
+    }
+}
+
+#[async_trait]
+impl QuestionSource for JsonDumpSource {
+    fn location(&self) -> String {
+        format!("file://{}", self.dir.join(&self.path).display())
+    }
+
+    // The whole dump is imported at once, only questions which are already stored are left out
+    async fn fetch(
+        &self,
+        log: &Logger,
+        _fetcher: &Fetcher,
+        options: &PageOptions,
+    ) -> Result<ScraperResult, ScrapeError> {
+        info!(log, "Reading dump   => {}", &self.path);
+        let json = tokio::fs::read_to_string(self.resolve().await?)
+            .await
+            .map_err(|err| ScrapeError::Io(format!("{}: {}", self.path, err)))?;
+        // the whole dump counts as a single page
+        options.page_fetched(1);
+        let questions = JsonDumpSource::parse(&json)?
+            .into_iter()
+            .filter(|question| !options.known_ids.contains(&question.stack_id))
+            .collect::<Vec<_>>();
+
+        Ok(ScraperResult {
+            unique_tags: count_tags(&questions),
+            questions,
+            skipped: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_tags, JsonDumpSource};
+
+    #[test]
+    fn test_parse_dump() {
+        let json = r#"[{
+            "title": "How do I convert a String into a vector of bytes?",
+            "q_description": "What is the idiomatic way?",
+            "question_link": "https://stackoverflow.com/questions/74391702",
+            "votes": 1024,
+            "stack_id": 74391702,
+            "views": 2300000,
+            "tags": ["rust", "string"],
+            "answer": 12
+        }]"#;
+        let questions = JsonDumpSource::parse(json).unwrap();
+
+        assert_eq!(questions.len(), 1);
+        assert_eq!(questions[0].stack_id, 74391702);
+        assert_eq!(count_tags(&questions).get("rust"), Some(&1));
+    }
+
+    #[test]
+    fn test_parse_bad_dump() {
+        assert!(JsonDumpSource::parse(r#"[{"title": "no other fields"}]"#).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_dumps_stay_inside_the_dump_directory() {
+        let root = std::env::temp_dir().join(format!("dumps-{:08x}", rand::random::<u32>()));
+        let dir = root.join("dumps");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("dump.json"), "[]").unwrap();
+        std::fs::write(root.join("secret.json"), "[]").unwrap();
+        let source = |path: &str| JsonDumpSource {
+            dir: dir.clone(),
+            path: path.to_string(),
+        };
+
+        assert!(source("dump.json").resolve().await.is_ok());
+        assert!(source("./dump.json").resolve().await.is_ok());
+        assert!(source("../secret.json").resolve().await.is_err());
+        assert!(source(root.join("secret.json").to_str().unwrap()).resolve().await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
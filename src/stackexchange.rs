@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use parking_lot::{const_mutex, Mutex};
+use reqwest::Url;
+use select::document::Document;
+use serde::Deserialize;
+use serde_json::Value;
+use slog::{info, warn, Logger};
+
+use crate::config::ScraperConfig;
+use crate::error::ScrapeError;
+use crate::fetcher::Fetcher;
+use crate::models::{ScrapeTarget, ScrapedQuestion, ScraperResult, SkippedPost};
+use crate::scraper::PageOptions;
+use crate::source::{count_tags, QuestionSource};
+
+// The API answers with at most 100 questions per page
+pub const API_PAGE_SIZE: usize = 100;
+
+// q_description holds at most 1000 characters
+const DESCRIPTION_LENGTH: usize = 1000;
+
+// Backoffs requested by the API, kept per API base so they outlive a single scrape run
+static BACKOFF_UNTIL: Mutex<BTreeMap<String, Instant>> = const_mutex(BTreeMap::new());
+
+// Wrapper every API 2.3 response comes in, errors are reported in the same object
+#[derive(Deserialize, Debug)]
+pub struct ApiResponse {
+    #[serde(default)]
+    pub items: Vec<Value>,
+    #[serde(default)]
+    pub has_more: bool,
+    pub quota_remaining: Option<i32>,
+    pub backoff: Option<u64>,
+    pub error_id: Option<i32>,
+    pub error_name: Option<String>,
+    pub error_message: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiQuestion {
+    pub question_id: i32,
+    pub title: String,
+    pub link: String,
+    pub score: i32,
+    pub view_count: i32,
+    pub answer_count: i32,
+    pub tags: Vec<String>,
+    pub body: Option<String>,
+}
+
+// Titles and bodies come html encoded, only their text is kept
+fn html_text(html: &str) -> String {
+    Document::from(html)
+        .nth(0)
+        .map(|root| root.text().trim().to_string())
+        .unwrap_or_default()
+}
+
+impl From<ApiQuestion> for ScrapedQuestion {
+    fn from(question: ApiQuestion) -> ScrapedQuestion {
+        let description = question.body.as_deref().map(html_text).unwrap_or_default();
+        ScrapedQuestion {
+            title: html_text(&question.title),
+            q_description: description.chars().take(DESCRIPTION_LENGTH).collect(),
+            question_link: question.link,
+            votes: question.score,
+            stack_id: question.question_id,
+            views: question.view_count,
+            tags: question.tags.into_iter().collect(),
+            answer: question.answer_count,
+        }
+    }
+}
+
+pub fn parse_response(json: &str) -> Result<ApiResponse, ScrapeError> {
+    let response: ApiResponse = serde_json::from_str(json)
+        .map_err(|err| ScrapeError::Api(format!("unexpected response: {}", err)))?;
+    match response.error_id {
+        Some(error_id) => Err(ScrapeError::Api(format!(
+            "{} {}: {}",
+            error_id,
+            response.error_name.as_deref().unwrap_or("unknown"),
+            response.error_message.as_deref().unwrap_or_default()
+        ))),
+        None => Ok(response),
+    }
+}
+
+// Waits until the backoff the API asked for on a previous request has passed
+async fn wait_for_backoff(log: &Logger, base: &str) {
+    let until = BACKOFF_UNTIL.lock().get(base).copied();
+    if let Some(until) = until {
+        let now = Instant::now();
+        if until > now {
+            info!(log, "Backing off the API for {:?}", until - now);
+            actix_rt::time::sleep(until - now).await;
+        }
+    }
+}
+
+fn set_backoff(base: &str, seconds: u64) {
+    BACKOFF_UNTIL
+        .lock()
+        .insert(base.to_string(), Instant::now() + Duration::from_secs(seconds));
+}
+
+// Questions of a tag from the Stack Exchange API /questions method
+pub struct StackExchangeApiSource {
+    pub base: String,
+    pub key: Option<String>,
+    pub filter: String,
+    pub site: String,
+    pub tag: String,
+    pub sort: &'static str,
+}
+
+impl StackExchangeApiSource {
+    pub fn new(target: &ScrapeTarget, config: &ScraperConfig) -> Self {
+        let sort = match target.tab.as_str() {
+            "Active" => "activity",
+            "Votes" => "votes",
+            _ => "creation",
+        };
+        StackExchangeApiSource {
+            base: config.api_base.trim_end_matches('/').to_string(),
+            key: config.api_key.clone(),
+            filter: config.api_filter.clone(),
+            site: target.site.clone(),
+            tag: target.tag_title.clone(),
+            sort,
+        }
+    }
+
+    pub fn page_url(&self, page: usize) -> Result<Url, ScrapeError> {
+        let mut url = Url::parse_with_params(
+            &format!("{}/questions", self.base),
+            &[
+                ("order", "desc"),
+                ("sort", self.sort),
+                ("tagged", &self.tag),
+                ("site", &self.site),
+                ("filter", &self.filter),
+                ("page", &page.to_string()),
+                ("pagesize", &API_PAGE_SIZE.to_string()),
+            ],
+        )
+        .map_err(|_| ScrapeError::Api(format!("bad api base {}", self.base)))?;
+        if let Some(key) = &self.key {
+            url.query_pairs_mut().append_pair("key", key);
+        }
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl QuestionSource for StackExchangeApiSource {
+    fn location(&self) -> String {
+        format!("{}/questions?tagged={}&site={}", self.base, self.tag, self.site)
+    }
+
+    // Pages through the API until the requested total, a known question, the last page or the
+    // end of the quota is reached. Items which don't look like questions are skipped
+    async fn fetch(
+        &self,
+        log: &Logger,
+        fetcher: &Fetcher,
+        options: &PageOptions,
+    ) -> Result<ScraperResult, ScrapeError> {
+        let mut questions = Vec::new();
+        let mut skipped = Vec::new();
+
+        'pages: for page in 1..=options.max_pages {
+            wait_for_backoff(log, &self.base).await;
+            let url = self.page_url(page)?;
+            info!(log, "Fetching page  => {}", &url);
+            let response = parse_response(&fetcher.get_api_text(log, url.as_str()).await?)?;
+            options.page_fetched(page);
+
+            if let Some(backoff) = response.backoff {
+                warn!(log, "API asked to back off for {} seconds", backoff);
+                set_backoff(&self.base, backoff);
+            }
+
+            for item in response.items {
+                let post_id = item.get("question_id").map(|id| id.to_string());
+                let question = match serde_json::from_value::<ApiQuestion>(item) {
+                    Ok(question) => ScrapedQuestion::from(question),
+                    Err(err) => {
+                        warn!(log, "Skipping API item {:?}: {}", post_id, err);
+                        skipped.push(SkippedPost {
+                            post_id,
+                            reason: format!("unexpected api item: {}", err),
+                        });
+                        continue;
+                    }
+                };
+                if options.known_ids.contains(&question.stack_id) {
+                    info!(log, "Reached already known question {}", question.stack_id);
+                    break 'pages;
+                }
+                questions.push(question);
+                if questions.len() >= options.total {
+                    break 'pages;
+                }
+            }
+
+            if response.quota_remaining == Some(0) {
+                warn!(log, "API quota used up, stopping at page {}", page);
+                break;
+            }
+            if !response.has_more {
+                break;
+            }
+        }
+
+        Ok(ScraperResult {
+            unique_tags: count_tags(&questions),
+            questions,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::time::{Duration, Instant};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use slog::{o, Discard, Logger};
+
+    use super::{parse_response, StackExchangeApiSource};
+    use crate::config::ScraperConfig;
+    use crate::error::ScrapeError;
+    use crate::fetcher::Fetcher;
+    use crate::scraper::PageOptions;
+    use crate::source::QuestionSource;
+
+    // Serves recorded API responses, page n of /questions answers with the nth fixture
+    fn mock_api(fixtures: &'static [&'static str]) -> String {
+        let server = HttpServer::new(move || {
+            App::new().route(
+                "/2.3/questions",
+                web::get().to(move |query: web::Query<HashMap<String, String>>| async move {
+                    let page = query.get("page").and_then(|page| page.parse::<usize>().ok());
+                    match (page, query.get("tagged").map(String::as_str)) {
+                        (Some(page), Some("rust")) if page <= fixtures.len() => HttpResponse::Ok()
+                            .content_type("application/json; charset=utf-8")
+                            .body(fixtures[page - 1]),
+                        _ => HttpResponse::BadRequest().finish(),
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        format!("http://{}/2.3", address)
+    }
+
+    fn source(base: String) -> StackExchangeApiSource {
+        StackExchangeApiSource {
+            base,
+            key: None,
+            filter: "withbody".to_string(),
+            site: "stackoverflow".to_string(),
+            tag: "rust".to_string(),
+            sort: "creation",
+        }
+    }
+
+    fn fetcher() -> Fetcher {
+        Fetcher::new(&ScraperConfig::default()).unwrap()
+    }
+
+    fn options(total: usize) -> PageOptions {
+        PageOptions {
+            total,
+            max_pages: 10,
+            known_ids: HashSet::new(),
+            progress: None,
+        }
+    }
+
+    #[test]
+    fn test_page_url() {
+        let mut source = source("https://api.stackexchange.com/2.3".to_string());
+        source.key = Some("secret".to_string());
+        assert_eq!(
+            source.page_url(2).unwrap().as_str(),
+            "https://api.stackexchange.com/2.3/questions?order=desc&sort=creation&tagged=rust&site=stackoverflow&filter=withbody&page=2&pagesize=100&key=secret"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let result = parse_response(include_str!("../tests/fixtures/se_api_throttle.json"));
+
+        match result {
+            Err(ScrapeError::Api(cause)) => assert!(cause.starts_with("502 throttle_violation")),
+            _ => panic!("Throttle violation should be an api error"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_pages_and_honour_backoff() {
+        let base = mock_api(&[
+            include_str!("../tests/fixtures/se_api_questions_page1.json"),
+            include_str!("../tests/fixtures/se_api_questions_page2.json"),
+        ]);
+        let log = Logger::root(Discard, o!());
+
+        let started = Instant::now();
+        let result = source(base).fetch(&log, &fetcher(), &options(50)).await.unwrap();
+
+        assert!(
+            started.elapsed() >= Duration::from_secs(1),
+            "Second page should wait for the backoff of the first"
+        );
+        let ids = result.questions.iter().map(|q| q.stack_id).collect::<Vec<_>>();
+        assert_eq!(ids, [74391825, 74391790, 74391702]);
+
+        let question = &result.questions[0];
+        assert_eq!(
+            question.title,
+            "Why does the borrow checker reject this closure & how do I fix it?"
+        );
+        assert_eq!(
+            question.question_link,
+            "https://stackoverflow.com/questions/74391825/why-does-the-borrow-checker-reject-this-closure"
+        );
+        assert!(question.q_description.starts_with("I have a closure that captures"));
+        assert!(!question.q_description.contains("<p>"), "Body should be stored as text");
+        assert_eq!(question.votes, 2);
+        assert_eq!(question.answer, 1);
+        assert_eq!(question.views, 1243);
+        assert_eq!(result.unique_tags.get("rust"), Some(&3));
+
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].post_id.as_deref(), Some("74391650"));
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_stops_at_total_and_known_question() {
+        let base = mock_api(&[include_str!("../tests/fixtures/se_api_quota_spent.json")]);
+        let log = Logger::root(Discard, o!());
+
+        let result = source(base.clone()).fetch(&log, &fetcher(), &options(1)).await.unwrap();
+        assert_eq!(result.questions.len(), 1);
+
+        let mut options = options(50);
+        options.known_ids.insert(74391790);
+        let result = source(base).fetch(&log, &fetcher(), &options).await.unwrap();
+        let ids = result.questions.iter().map(|q| q.stack_id).collect::<Vec<_>>();
+        assert_eq!(ids, [74391825], "Walking should stop at the known question");
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_stops_when_quota_is_spent() {
+        // has_more is set, but asking for page 2 would be a bad request
+        let base = mock_api(&[include_str!("../tests/fixtures/se_api_quota_spent.json")]);
+        let log = Logger::root(Discard, o!());
+
+        let result = source(base).fetch(&log, &fetcher(), &options(50)).await.unwrap();
+        assert_eq!(result.questions.len(), 2);
+    }
+}